@@ -1,12 +1,28 @@
+use std::{error, fmt};
+
+/// The receivers of a channel are gone.
+/// Whoever sends to it has nobody to work for anymore and should wind down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Closed;
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The channel is closed")
+    }
+}
+
+impl error::Error for Closed {}
+
 pub trait SenderExt {
     type Msg;
-    fn send_or_panic(&self, msg: Self::Msg) -> impl Future<Output = ()>;
+    /// Send the message, or fail once all receivers are dropped.
+    fn deliver(&self, msg: Self::Msg) -> impl Future<Output = Result<(), Closed>>;
 }
 
 impl<T> SenderExt for async_channel::Sender<T> {
     type Msg = T;
 
-    async fn send_or_panic(&self, msg: Self::Msg) -> () {
-        self.send(msg).await.unwrap()
+    async fn deliver(&self, msg: Self::Msg) -> Result<(), Closed> {
+        self.send(msg).await.map_err(|_| Closed)
     }
 }
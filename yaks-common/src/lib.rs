@@ -2,10 +2,12 @@ mod channel;
 mod duration;
 mod range;
 mod response;
+mod sanitize;
 mod str;
 
-pub use channel::SenderExt;
+pub use channel::{Closed, SenderExt};
 pub use duration::RandomDuration;
 pub use range::Range;
 pub use response::ResponseExt;
+pub use sanitize::{MAX_COMPONENT_BYTES, Policy, Sanitizer};
 pub use str::StrExt;
@@ -1,9 +1,15 @@
 use std::io::Read;
 
-use anyhow::bail;
+use anyhow::anyhow;
+use bytes::Bytes;
 use flate2::read::GzDecoder;
 use reqwest::Response;
 use serde::Deserialize;
+
+/// Decode JSON bodies that may be sent gzipped without telling.
+///
+/// Also implemented for `Bytes` so bodies read from elsewhere (like a cache)
+/// are decoded the same way.
 pub trait ResponseExt {
     fn sneaky_json<T>(self) -> impl Future<Output = anyhow::Result<T>>
     where
@@ -16,7 +22,16 @@ impl ResponseExt for Response {
         for<'de> T: Deserialize<'de>,
     {
         let bytes = self.bytes().await?;
-        decode_using_strats(&bytes, [decode_plain, decode_gziped])
+        bytes.sneaky_json().await
+    }
+}
+
+impl ResponseExt for Bytes {
+    async fn sneaky_json<T>(self) -> anyhow::Result<T>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        decode_using_strats(&self, [decode_plain, decode_gziped])
     }
 }
 
@@ -38,7 +53,16 @@ where
             }
         }
     }
-    bail!("{sink:?}")
+    let message = sink
+        .iter()
+        .map(|e| format!("{e:#}"))
+        .collect::<Vec<_>>()
+        .join("; ");
+    // the first error stays in the chain, so it can still be told apart
+    match sink.into_iter().next() {
+        Some(first) => Err(first.context(message)),
+        None => Err(anyhow!(message)),
+    }
 }
 
 fn decode_plain<T>(bytes: &[u8]) -> anyhow::Result<T>
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::StrExt;
+
+/// Most file systems limit a path component to 255 bytes.
+pub const MAX_COMPONENT_BYTES: usize = 255;
+
+/// Extensions longer than this are not worth preserving when truncating.
+const MAX_EXTENSION_BYTES: usize = 16;
+
+/// Which characters and names are allowed in paths.
+///
+/// `/`, NUL and other control characters are never allowed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    /// Paths that work on Windows, NTFS and exFAT too.
+    /// Reserved characters are replaced with their full-width forms,
+    /// trailing dots and spaces are removed and reserved names like `CON` are escaped.
+    #[default]
+    Portable,
+    /// Only what POSIX file systems need.
+    Posix,
+    /// POSIX plus the given replacements, written as a map of them.
+    #[serde(untagged)]
+    Custom(HashMap<char, String>),
+}
+
+impl Policy {
+    /// The replacement for the given char, if it needs to be replaced.
+    pub fn replacement(&self, c: char) -> Option<&str> {
+        match self {
+            Policy::Portable => match c {
+                '\\' => Some("＼"),
+                ':' => Some("："),
+                '*' => Some("＊"),
+                '?' => Some("？"),
+                '"' => Some("＂"),
+                '<' => Some("＜"),
+                '>' => Some("＞"),
+                '|' => Some("｜"),
+                _ => None,
+            },
+            Policy::Posix => None,
+            Policy::Custom(map) => map.get(&c).map(String::as_str),
+        }
+    }
+
+    /// Refuse custom replacements that are not allowed in paths themselves,
+    /// like a `/` which would create a folder.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let Policy::Custom(map) = self else {
+            return Ok(());
+        };
+        for (c, replacement) in map {
+            if let Some(bad) = replacement
+                .chars()
+                .find(|&r| r == '/' || r == '\\' || r.is_control())
+            {
+                bail!(
+                    "The replacement of `{}` can not contain `{}`",
+                    c.escape_default(),
+                    bad.escape_default()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Turns strings into paths according to a policy.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Sanitizer {
+    /// Also read from `sanitize` and `replace`, the keys of the conf.
+    #[serde(alias = "sanitize", alias = "replace")]
+    pub policy: Policy,
+    /// Normalize paths into Unicode NFC, so the same title always maps to the same bytes.
+    pub nfc: bool,
+}
+
+impl Sanitizer {
+    pub fn new(policy: Policy, nfc: bool) -> Self {
+        Self { policy, nfc }
+    }
+
+    /// Make a value safe to be inserted into a path component.
+    pub fn value(&self, value: &str) -> String {
+        value.to_path_safe(&self.policy).into_owned()
+    }
+
+    /// Make every component of a relative `/`-separated path valid.
+    ///
+    /// Components are truncated to `MAX_COMPONENT_BYTES` without breaking
+    /// chars or the extension. `reserved` bytes are left in the last component
+    /// for suffixes appended later.
+    pub fn path(&self, path: &str, reserved: usize) -> String {
+        let path = if self.nfc {
+            path.nfc().collect()
+        } else {
+            path.to_string()
+        };
+        let mut components = path.split('/').peekable();
+        let mut res = String::with_capacity(path.len());
+        while let Some(component) = components.next() {
+            let last = components.peek().is_none();
+            let max = if last {
+                MAX_COMPONENT_BYTES.saturating_sub(reserved)
+            } else {
+                MAX_COMPONENT_BYTES
+            };
+            res.push_str(&self.component(component, max));
+            if !last {
+                res.push('/');
+            }
+        }
+        res
+    }
+
+    fn component(&self, component: &str, max: usize) -> String {
+        let portable = matches!(self.policy, Policy::Portable);
+        let mut component = if portable {
+            self::portable(component)
+        } else {
+            component.to_string()
+        };
+        if component.len() > max {
+            component = truncate(&component, max);
+            // the cut may end with dots or spaces
+            if portable {
+                component = self::portable(&component);
+            }
+        }
+        component
+    }
+}
+
+/// Windows strips trailing dots and spaces and refuses device names.
+fn portable(component: &str) -> String {
+    const RESERVED: [&str; 22] = [
+        "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+        "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+    ];
+    let trimmed = component.trim_end_matches(['.', ' ']);
+    if trimmed.is_empty() {
+        return "_".into();
+    }
+    let (stem, rest) = match trimmed.find('.') {
+        Some(dot) => trimmed.split_at(dot),
+        None => (trimmed, ""),
+    };
+    if RESERVED
+        .iter()
+        .any(|r| r.eq_ignore_ascii_case(stem.trim_end()))
+    {
+        format!("{stem}_{rest}")
+    } else {
+        trimmed.to_string()
+    }
+}
+
+fn truncate(component: &str, max: usize) -> String {
+    let ext = match component.rfind('.') {
+        Some(dot) if dot > 0 && component.len() - dot <= MAX_EXTENSION_BYTES => &component[dot..],
+        _ => "",
+    };
+    let stem = &component[..component.len() - ext.len()];
+    let mut truncated = stem
+        .truncate_bytes(max.saturating_sub(ext.len()))
+        .to_string();
+    truncated.push_str(ext);
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn custom(pairs: &[(char, &str)]) -> Policy {
+        Policy::Custom(pairs.iter().map(|&(c, r)| (c, r.to_string())).collect())
+    }
+
+    #[test]
+    fn replaces_by_policy() {
+        let portable = Sanitizer::default();
+        assert_eq!(portable.value("a:b?c|d"), "a：b？c｜d");
+        let posix = Sanitizer::new(Policy::Posix, false);
+        assert_eq!(posix.value("a:b?c|d"), "a:b?c|d");
+        let custom = Sanitizer::new(custom(&[(':', "-")]), false);
+        assert_eq!(custom.value("a:b?c"), "a-b?c");
+        // whatever the policy, values never create folders nor hold control chars
+        for sanitizer in [portable, posix, custom] {
+            assert_eq!(sanitizer.value("a/b\nc"), "a／bc");
+        }
+    }
+
+    #[test]
+    fn refuses_unsafe_replacements() {
+        assert!(custom(&[(':', "-"), ('?', "")]).validate().is_ok());
+        assert!(custom(&[(':', "a/b")]).validate().is_err());
+        assert!(custom(&[(':', "\\")]).validate().is_err());
+        assert!(custom(&[(':', "\t")]).validate().is_err());
+        assert!(Policy::Portable.validate().is_ok());
+    }
+
+    #[test]
+    fn escapes_reserved_names() {
+        let portable = Sanitizer::default();
+        assert_eq!(portable.path("CON/con.txt", 0), "CON_/con_.txt");
+        assert_eq!(portable.path("lpt1", 0), "lpt1_");
+        assert_eq!(portable.path("CONSOLE/trailing. .", 0), "CONSOLE/trailing");
+        assert_eq!(portable.path("...", 0), "_");
+        let posix = Sanitizer::new(Policy::Posix, false);
+        assert_eq!(posix.path("CON/trailing.", 0), "CON/trailing.");
+    }
+
+    #[test]
+    fn normalizes_into_nfc() {
+        let decomposed = "e\u{301}";
+        let nfc = Sanitizer::new(Policy::Posix, true);
+        assert_eq!(nfc.path(decomposed, 0), "\u{e9}");
+        let raw = Sanitizer::new(Policy::Posix, false);
+        assert_eq!(raw.path(decomposed, 0), decomposed);
+    }
+
+    #[test]
+    fn truncates_by_bytes() {
+        let sanitizer = Sanitizer::default();
+        // 3 bytes per char, so the limit falls within one
+        let long = format!("{}.png", "あ".repeat(100));
+        let path = sanitizer.path(&long, 0);
+        assert!(path.len() <= MAX_COMPONENT_BYTES);
+        assert!(path.len() > MAX_COMPONENT_BYTES - 3);
+        assert!(path.ends_with("あ.png"));
+
+        let path = sanitizer.path(&long, 6);
+        assert!(path.len() <= MAX_COMPONENT_BYTES - 6);
+        assert!(path.ends_with(".png"));
+
+        // only the last component leaves room for suffixes
+        let folder = "a".repeat(300);
+        let path = sanitizer.path(&format!("{folder}/b"), 6);
+        assert_eq!(path, format!("{}/b", &folder[..MAX_COMPONENT_BYTES]));
+
+        // extensions too long to be ones are cut like the rest
+        let path = sanitizer.path(&format!("a.{}", "b".repeat(300)), 0);
+        assert_eq!(path.len(), MAX_COMPONENT_BYTES);
+    }
+}
@@ -1,12 +1,41 @@
-use cow_utils::CowUtils;
 use std::borrow::Cow;
 
+use crate::Policy;
+
 pub trait StrExt<'a> {
-    fn to_path_safe(&'a self) -> Cow<'a, str>;
+    /// Replace the characters that are not allowed in a path component.
+    fn to_path_safe(&'a self, policy: &Policy) -> Cow<'a, str>;
+    /// The longest prefix that fits in `max` bytes without splitting a char.
+    fn truncate_bytes(&'a self, max: usize) -> &'a str;
 }
 
 impl<'a> StrExt<'a> for str {
-    fn to_path_safe(&'a self) -> Cow<'a, str> {
-        self.cow_replace("/", "／")
+    fn to_path_safe(&'a self, policy: &Policy) -> Cow<'a, str> {
+        let unsafe_char = |c: char| c == '/' || c.is_control() || policy.replacement(c).is_some();
+        if !self.contains(unsafe_char) {
+            return Cow::Borrowed(self);
+        }
+        let mut safe = String::with_capacity(self.len());
+        for c in self.chars() {
+            match policy.replacement(c) {
+                Some(replacement) => safe.push_str(replacement),
+                None if c == '/' => safe.push('／'),
+                // control chars are dropped
+                None if c.is_control() => {}
+                None => safe.push(c),
+            }
+        }
+        Cow::Owned(safe)
+    }
+
+    fn truncate_bytes(&'a self, max: usize) -> &'a str {
+        if self.len() <= max {
+            return self;
+        }
+        let mut end = max;
+        while !self.is_char_boundary(end) {
+            end -= 1;
+        }
+        &self[..end]
     }
 }
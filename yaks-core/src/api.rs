@@ -0,0 +1,95 @@
+use anyhow::anyhow;
+use bytes::Bytes;
+use reqwest::{
+    Response, StatusCode,
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+};
+
+use crate::{
+    cache::{Cache, CachePolicy, Entry, Meta},
+    rate::RateLimiter,
+};
+
+/// Access to the API, paced by the rate limiter and backed by the cache.
+pub struct Api {
+    limiter: RateLimiter,
+    cache: Option<Cache>,
+    policy: CachePolicy,
+}
+
+impl Api {
+    pub fn new(limiter: RateLimiter, policy: CachePolicy) -> Self {
+        let cache = match policy {
+            CachePolicy::Off => None,
+            _ => Cache::open(),
+        };
+        Self {
+            limiter,
+            cache,
+            policy,
+        }
+    }
+
+    /// Get the body of the given URL, from the cache if possible.
+    pub async fn get(&self, url: &str) -> anyhow::Result<Bytes> {
+        let ttl = match self.policy {
+            CachePolicy::Off => {
+                let resp = self.limiter.get(url, HeaderMap::new()).await?;
+                return Ok(resp.bytes().await?);
+            }
+            CachePolicy::Offline => {
+                let entry = match &self.cache {
+                    Some(cache) => cache.load(url).await?,
+                    None => None,
+                };
+                let entry = entry.ok_or(anyhow!("`{url}` is not cached"))?;
+                return Ok(entry.body);
+            }
+            CachePolicy::Revalidate(ttl) => ttl,
+        };
+        let Some(cache) = &self.cache else {
+            let resp = self.limiter.get(url, HeaderMap::new()).await?;
+            return Ok(resp.bytes().await?);
+        };
+
+        // a broken entry is as good as a missing one
+        let cached = cache.load(url).await.ok().flatten();
+        let mut headers = HeaderMap::new();
+        if let Some(Entry { meta, body }) = &cached {
+            if meta.age() < ttl {
+                return Ok(body.clone());
+            }
+            if let Some(etag) = &meta.etag {
+                headers.insert(header::IF_NONE_MATCH, HeaderValue::from_str(etag)?);
+            }
+            if let Some(last_modified) = &meta.last_modified {
+                headers.insert(
+                    header::IF_MODIFIED_SINCE,
+                    HeaderValue::from_str(last_modified)?,
+                );
+            }
+        }
+
+        let resp = self.limiter.get(url, headers).await?;
+        if resp.status() == StatusCode::NOT_MODIFIED
+            && let Some(Entry { mut meta, body }) = cached
+        {
+            // the cache is best-effort, the body is here anyway
+            let _ = cache.refresh(&mut meta).await;
+            return Ok(body);
+        }
+        let etag = header_str(&resp, header::ETAG);
+        let last_modified = header_str(&resp, header::LAST_MODIFIED);
+        let entry = Entry {
+            meta: Meta::new(url, etag, last_modified),
+            body: resp.bytes().await?,
+        };
+        let _ = cache.store(&entry).await;
+        Ok(entry.body)
+    }
+}
+
+fn header_str(resp: &Response, name: HeaderName) -> Option<String> {
+    let value = resp.headers().get(name)?.to_str().ok()?;
+    Some(value.to_string())
+}
@@ -0,0 +1,125 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_with::{DurationSeconds, serde_as};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+
+/// How responses of the API are cached between runs.
+#[serde_as]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CachePolicy {
+    /// Never read nor write the cache.
+    Off,
+    /// Never send requests to the API. Only cached responses are used.
+    Offline,
+    /// Trust cached responses younger than the given age, in seconds.
+    /// Older ones are revalidated with conditional requests.
+    #[serde(untagged)]
+    Revalidate(#[serde_as(as = "DurationSeconds<u64>")] Duration),
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        Self::Revalidate(Duration::ZERO)
+    }
+}
+
+/// API responses stored under the cache folder, keyed by URL.
+///
+/// Each entry is a pair of files: `{key}.body` holding the raw payload
+/// and `{key}.json` holding the validators. The latter is written last
+/// so an entry without it is never trusted.
+pub struct Cache {
+    root: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Meta {
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// Seconds since epoch when the payload was last confirmed by the server.
+    pub fetched: u64,
+}
+
+pub struct Entry {
+    pub meta: Meta,
+    pub body: Bytes,
+}
+
+impl Meta {
+    pub fn new(url: &str, etag: Option<String>, last_modified: Option<String>) -> Self {
+        Self {
+            url: url.to_string(),
+            etag,
+            last_modified,
+            fetched: now(),
+        }
+    }
+
+    pub fn age(&self) -> Duration {
+        Duration::from_secs(now().saturating_sub(self.fetched))
+    }
+}
+
+impl Cache {
+    /// Locate the cache folder. Returns `None` if the platform has none.
+    pub fn open() -> Option<Self> {
+        let root = dirs_next::cache_dir()?.join("yaks").join("api");
+        Some(Self { root })
+    }
+
+    pub async fn load(&self, url: &str) -> anyhow::Result<Option<Entry>> {
+        let (meta_path, body_path) = self.paths(url);
+        if !fs::try_exists(&meta_path).await? {
+            return Ok(None);
+        }
+        let meta: Meta = serde_json::from_slice(&fs::read(meta_path).await?)?;
+        // different URLs sharing the same key. unlikely but possible
+        if meta.url != url {
+            return Ok(None);
+        }
+        let body = fs::read(body_path).await?.into();
+        Ok(Some(Entry { meta, body }))
+    }
+
+    pub async fn store(&self, entry: &Entry) -> anyhow::Result<()> {
+        let (meta_path, body_path) = self.paths(&entry.meta.url);
+        fs::create_dir_all(&self.root).await?;
+        // invalidate the old entry before touching the body
+        if fs::try_exists(&meta_path).await? {
+            fs::remove_file(&meta_path).await?;
+        }
+        fs::write(body_path, &entry.body).await?;
+        fs::write(meta_path, serde_json::to_vec(&entry.meta)?).await?;
+        Ok(())
+    }
+
+    /// Mark the entry as confirmed by the server just now.
+    pub async fn refresh(&self, meta: &mut Meta) -> anyhow::Result<()> {
+        meta.fetched = now();
+        let (meta_path, _) = self.paths(&meta.url);
+        fs::write(meta_path, serde_json::to_vec(meta)?).await?;
+        Ok(())
+    }
+
+    fn paths(&self, url: &str) -> (PathBuf, PathBuf) {
+        let key = format!("{:x}", Sha256::digest(url.as_bytes()));
+        let meta = self.root.join(format!("{key}.json"));
+        let body = self.root.join(format!("{key}.body"));
+        (meta, body)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,223 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+};
+use ustr::Ustr;
+use yaks_common::{Range, Sanitizer};
+
+use crate::{
+    CHECKPOINT_NAME,
+    file::{Collected, Collision, File, FileID, Skip},
+    post::{Post, PostID, Profile},
+};
+
+/// What a run is about. A checkpoint is only resumed by the same run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Identity {
+    pub url: String,
+    pub linked: bool,
+    pub range: Range,
+    pub format: String,
+    pub sanitizer: Sanitizer,
+    pub collision: Collision,
+}
+
+/// A line of the journal.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "entry", rename_all = "snake_case")]
+enum Entry {
+    Run(Identity),
+    /// All posts of the run, by profile.
+    Scraped {
+        posts: Vec<(Profile, Vec<Post>)>,
+    },
+    /// A post whose files have all been collected.
+    Browsed {
+        platform: Ustr,
+        post: PostID,
+        files: Vec<File>,
+        skipped: Vec<(File, Skip)>,
+    },
+    /// A file that has been fully downloaded.
+    Finished {
+        file: FileID,
+    },
+}
+
+/// What an interrupted run had done.
+pub(crate) struct Resumed {
+    pub posts: Vec<(Profile, Vec<Post>)>,
+    pub browsed: HashMap<(Ustr, PostID), Collected>,
+    pub finished: HashSet<FileID>,
+}
+
+/// Journal of a run, saved as JSON lines in the root of the output folder.
+///
+/// Every line is written as soon as it is known, so a run killed at any point
+/// can be resumed without scraping the posts nor browsing them again.
+/// The journal is removed once the run has downloaded everything it collected.
+pub(crate) struct Checkpoint {
+    path: PathBuf,
+    lock: Mutex<()>,
+    /// Files queued but not downloaded yet.
+    pending: AtomicUsize,
+    /// Whether a post failed to be browsed.
+    failed: AtomicBool,
+}
+
+impl Checkpoint {
+    /// Start the journal of a new run, replacing the one of any previous run.
+    pub async fn create(
+        out: &Path,
+        identity: Identity,
+        posts: Vec<(Profile, Vec<Post>)>,
+    ) -> anyhow::Result<Self> {
+        fs::create_dir_all(out).await?;
+        let checkpoint = Self::new(out);
+        let mut journal = line(&Entry::Run(identity))?;
+        journal.push_str(&line(&Entry::Scraped { posts })?);
+        fs::write(&checkpoint.path, journal).await?;
+        Ok(checkpoint)
+    }
+
+    /// Continue the journal left in `out` by an interrupted run.
+    ///
+    /// Returns `None` if there is nothing to resume. Lines that can not be parsed,
+    /// like the last one of a killed run, are ignored.
+    pub async fn resume(
+        out: &Path,
+        identity: &Identity,
+    ) -> anyhow::Result<Option<(Self, Resumed)>> {
+        let checkpoint = Self::new(out);
+        let journal = match fs::read_to_string(&checkpoint.path).await {
+            Ok(journal) => journal,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut entries = journal
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Entry>(line).ok());
+        match entries.next() {
+            Some(Entry::Run(run)) if run == *identity => {}
+            Some(Entry::Run(run)) => bail!(
+                "The checkpoint in the output folder belongs to another run ({})",
+                run.url
+            ),
+            _ => return Ok(None),
+        }
+        let Some(Entry::Scraped { posts }) = entries.next() else {
+            return Ok(None);
+        };
+        let mut resumed = Resumed {
+            posts,
+            browsed: HashMap::new(),
+            finished: HashSet::new(),
+        };
+        for entry in entries {
+            match entry {
+                Entry::Browsed {
+                    platform,
+                    post,
+                    files,
+                    skipped,
+                } => {
+                    resumed.browsed.insert((platform, post), (files, skipped));
+                }
+                Entry::Finished { file } => {
+                    resumed.finished.insert(file);
+                }
+                Entry::Run(_) | Entry::Scraped { .. } => {}
+            }
+        }
+        Ok(Some((checkpoint, resumed)))
+    }
+
+    fn new(out: &Path) -> Self {
+        Self {
+            path: out.join(CHECKPOINT_NAME),
+            lock: Mutex::new(()),
+            pending: AtomicUsize::new(0),
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    /// Count files queued again from the journal.
+    pub fn queued(&self, files: usize) {
+        self.pending.fetch_add(files, Ordering::Relaxed);
+    }
+
+    /// Record a browsed post with its files, which are queued.
+    pub async fn browsed(
+        &self,
+        platform: Ustr,
+        post: PostID,
+        (files, skipped): &Collected,
+    ) -> anyhow::Result<()> {
+        self.queued(files.len());
+        self.append(&Entry::Browsed {
+            platform,
+            post,
+            files: files.clone(),
+            skipped: skipped.clone(),
+        })
+        .await
+    }
+
+    /// Remember that a post could not be browsed, so the journal is kept for it.
+    pub fn failed(&self) {
+        self.failed.store(true, Ordering::Relaxed);
+    }
+
+    /// Record a downloaded file.
+    pub async fn finished(&self, file: &File) -> anyhow::Result<()> {
+        self.pending.fetch_sub(1, Ordering::Relaxed);
+        self.append(&Entry::Finished { file: file.id() }).await
+    }
+
+    /// Remove the journal if the run is complete.
+    /// It is kept if the run was cancelled, or if anything is left to do.
+    pub async fn close(&self, cancelled: bool) -> anyhow::Result<()> {
+        if cancelled
+            || self.failed.load(Ordering::Relaxed)
+            || self.pending.load(Ordering::Relaxed) > 0
+        {
+            return Ok(());
+        }
+        let _guard = self.lock.lock().await;
+        match fs::remove_file(&self.path).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn append(&self, entry: &Entry) -> anyhow::Result<()> {
+        let line = line(entry)?;
+        // lines from concurrent browsers and workers must not interleave
+        let _guard = self.lock.lock().await;
+        let mut journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        journal.write_all(line.as_bytes()).await?;
+        // written in the background otherwise, possibly after the next line
+        journal.flush().await?;
+        Ok(())
+    }
+}
+
+fn line(entry: &Entry) -> anyhow::Result<String> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    Ok(line)
+}
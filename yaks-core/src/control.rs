@@ -0,0 +1,89 @@
+use std::future::Future;
+
+use tokio::sync::watch;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use yaks_common::Closed;
+
+/// Steers a running engine from the outside.
+///
+/// Clones control the same run. Pausing stops workers and browsers from taking
+/// new files and posts, while the ones in flight are finished. Cancelling stops
+/// everything and removes the partial downloads.
+#[derive(Debug, Clone)]
+pub struct Control {
+    cancel: CancellationToken,
+    paused: watch::Sender<bool>,
+    tasks: TaskTracker,
+}
+
+impl Default for Control {
+    fn default() -> Self {
+        Self {
+            cancel: CancellationToken::new(),
+            paused: watch::Sender::new(false),
+            tasks: TaskTracker::new(),
+        }
+    }
+}
+
+impl Control {
+    /// Stop the run. The partial downloads are removed.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// Let the files and posts in flight finish, but start no new ones.
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Resolves once every task of the run has ended.
+    pub async fn shutdown(&self) {
+        self.tasks.close();
+        self.tasks.wait().await;
+    }
+
+    /// Resolves once the run is cancelled.
+    pub(crate) async fn cancelled(&self) {
+        self.cancel.cancelled().await;
+    }
+
+    /// Wait while the run is paused.
+    /// Returns `false` if the run is cancelled meanwhile, or already.
+    pub(crate) async fn proceed(&self) -> bool {
+        let mut paused = self.paused.subscribe();
+        tokio::select! {
+            _ = self.cancel.cancelled() => false,
+            // the sender lives in `self`, so waiting never fails
+            _ = paused.wait_for(|paused| !paused) => !self.is_cancelled(),
+        }
+    }
+
+    /// Spawn a task of the run, so shutting down waits for it.
+    ///
+    /// A task finding nobody to send to anymore, typically because the UI dropped
+    /// the receiver of events, cancels the whole run.
+    pub(crate) fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = Result<(), Closed>> + Send + 'static,
+    {
+        let control = self.clone();
+        self.tasks.spawn(async move {
+            if task.await.is_err() {
+                control.cancel();
+            }
+        });
+    }
+}
@@ -1,104 +1,563 @@
-use std::path::PathBuf;
+use std::{
+    mem,
+    sync::{Arc, Mutex},
+};
 
+use anyhow::ensure;
 use async_channel::{self, Receiver, Sender};
-use yaks_common::{Range, SenderExt};
+use tokio_util::sync::{CancellationToken, DropGuard};
+use ustr::Ustr;
+use yaks_common::SenderExt;
 
 use crate::{
-    Event, File, FileID, file,
-    post::{self},
-    worker::{self, Prog},
+    Archive, Control, EngineOptions, Event, File, FileID, Kemono, Local, REPORT_NAME, Skip, Source,
+    Storage,
+    checkpoint::{Checkpoint, Identity, Resumed},
+    extract::Extractor,
+    file::{self, Browse, Browsed, Known, Layout},
+    hook::HookRunner,
+    ledger::Ledger,
+    rate::Pace,
+    reorganize::{self, Reorganization},
+    report,
+    verify::{self, Audit},
+    worker::{self, Duties, Prog},
 };
 
-#[derive(Default)]
-pub struct Engine {}
+/// Runs downloads, verifications and reorganizations with the same options,
+/// from the same source.
+#[derive(Debug)]
+pub struct Engine<S = Kemono> {
+    options: EngineOptions,
+    source: Arc<S>,
+    /// Where downloads are written instead of the output folder or the archive
+    /// of the options, which every run starts anew. Verifications and
+    /// reorganizations look at the output folder whatever it is.
+    storage: Option<Arc<dyn Storage>>,
+}
+
+impl<S> Clone for Engine<S> {
+    fn clone(&self) -> Self {
+        Self {
+            options: self.options.clone(),
+            source: Arc::clone(&self.source),
+            storage: self.storage.clone(),
+        }
+    }
+}
 
 impl Engine {
-    pub fn start(
-        self,
-        url: String,
-        range: Range,
-        out: PathBuf,
-        format: String,
-        save_text: bool,
-        workers: u8,
-    ) -> Receiver<crate::Result<Event>> {
-        // event chann (for TUI/GUI)
-        let (events, event_rx) = async_channel::unbounded();
+    /// Validate the options for the runs to come, downloading from the site they point to.
+    pub fn new(options: EngineOptions) -> anyhow::Result<Self> {
+        let source = Kemono::new(&options.site, options.cache, options.retry);
+        Self::with_source(options, source)
+    }
+}
+
+impl<S: Source> Engine<S> {
+    /// Validate the options for the runs to come, downloading from `source`.
+    ///
+    /// The URL is only checked when given, since reorganizing does without it.
+    /// Files are saved into the output folder, or packed if the options say so.
+    pub fn with_source(options: EngineOptions, source: S) -> anyhow::Result<Self> {
+        options.validate()?;
+        if !options.url.is_empty() {
+            source.parse_url(&options.url)?;
+        }
+        Ok(Self {
+            options,
+            source: Arc::new(source),
+            storage: None,
+        })
+    }
+
+    /// Write the downloads into `storage` instead.
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> anyhow::Result<Self> {
+        ensure!(
+            self.options.extract.is_none() || storage.on_disk(),
+            "Archives can only be extracted from files on disk"
+        );
+        self.storage = Some(storage);
+        Ok(self)
+    }
+
+    pub fn options(&self) -> &EngineOptions {
+        &self.options
+    }
+
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    /// Start downloading. Progress and errors are reported through the returned receiver,
+    /// and the run is steered with the returned control.
+    pub fn start(self) -> (Receiver<crate::Result<Event>>, Control) {
+        let EngineOptions {
+            url,
+            linked,
+            range,
+            ignore_ledger,
+            out,
+            format,
+            sanitizer,
+            collision,
+            save_text,
+            save_meta,
+            workers,
+            hooks,
+            extract,
+            dry_run,
+            resume,
+            pack,
+            ..
+        } = self.options;
+        let source = self.source;
+        let storage = self.storage;
+        let control = Control::default();
+        // event chann (for TUI/GUI), counted on the way
+        let (events, counted) = async_channel::unbounded();
+        let (ui, event_rx) = async_channel::unbounded();
+        let save = (!dry_run).then(|| out.join(REPORT_NAME));
+        report::listen_events(counted, ui, save, &control);
         // chann for Error
         let (error_tx, errors) = async_channel::unbounded();
-        listen_errors(errors, events.clone());
+        let hooks = Arc::new(HookRunner::new(
+            hooks,
+            out.to_string_lossy().into_owned(),
+            events.clone(),
+            control.clone(),
+        ));
+        listen_errors(errors, Arc::clone(&hooks), events.clone(), &control);
+        // the rate of requests to the source, which outlives the run
+        let done = CancellationToken::new();
+        if let Some(rates) = source.rates() {
+            listen_rates(rates, done.clone(), events.clone(), &control);
+        }
+        // dropped once the run is over, wherever it ends
+        let done = done.drop_guard();
+        // the storage opened by the run, until the downloads take it over
+        let opened = Arc::new(Mutex::new(None::<Arc<dyn Storage>>));
+        let unfinished = Arc::clone(&opened);
+        let storage_events = events.clone();
 
-        tokio::spawn(async move {
-            // parsing url
-            let (platform, user_id) = match post::parse_url(&url) {
-                Ok(parsed) => parsed,
-                Err(e) => {
-                    error_tx.send_or_panic(crate::Error::Profile(e)).await;
-                    return;
-                }
+        let ctrl = control.clone();
+        let run = async move {
+            let identity = Identity {
+                url: url.clone(),
+                linked,
+                range,
+                format: format.to_string(),
+                sanitizer: sanitizer.clone(),
+                collision,
             };
-            // fetching profile
-            let profile = match post::fetch_profile(platform, user_id).await {
-                Ok(profile) => profile,
-                Err(e) => {
-                    error_tx.send_or_panic(crate::Error::Profile(e)).await;
-                    return;
+            // the run interrupted before, if asked to. dry runs leave it alone.
+            let mut resumed = None;
+            if resume && !dry_run {
+                match Checkpoint::resume(&out, &identity).await {
+                    Ok(checkpoint) => resumed = checkpoint,
+                    Err(e) => {
+                        error_tx.deliver(crate::Error::Checkpoint(e)).await?;
+                        return Ok(());
+                    }
                 }
-            };
-            events.send_or_panic(Ok(Event::Profile)).await;
-            // scrape all posts
-            let posts = match post::scrape_posts(platform, user_id, profile.post_count, range).await
-            {
-                Ok(posts) => {
-                    events.send_or_panic(Ok(Event::Posts(posts.len()))).await;
-                    posts
+            }
+            let scraped = match &mut resumed {
+                Some((_, resumed)) => {
+                    events.deliver(Ok(Event::Profile)).await?;
+                    for (_, posts) in &resumed.posts {
+                        events.deliver(Ok(Event::Posts(posts.len()))).await?;
+                    }
+                    mem::take(&mut resumed.posts)
                 }
+                None => {
+                    // parsing url
+                    let (platform, user_id) = match source.parse_url(&url) {
+                        Ok(parsed) => parsed,
+                        Err(e) => {
+                            error_tx.deliver(crate::Error::Profile(e)).await?;
+                            return Ok(());
+                        }
+                    };
+                    // fetching profile
+                    let profile = match source.profile(platform, user_id).await {
+                        Ok(profile) => profile,
+                        Err(e) => {
+                            error_tx.deliver(crate::Error::Profile(e)).await?;
+                            return Ok(());
+                        }
+                    };
+                    let mut profiles = vec![profile];
+                    // the artist is downloaded whatever happens to their linked accounts
+                    if linked {
+                        match source.linked(&profiles[0]).await {
+                            Ok(linked) => {
+                                for profile in linked {
+                                    match profile {
+                                        Ok(profile) => profiles.push(profile),
+                                        Err(e) => {
+                                            error_tx.deliver(crate::Error::Linked(e)).await?;
+                                        }
+                                    }
+                                }
+                            }
+                            Err(e) => error_tx.deliver(crate::Error::Linked(e)).await?,
+                        }
+                    }
+                    events.deliver(Ok(Event::Profile)).await?;
+                    // scrape all posts
+                    let mut scraped = Vec::new();
+                    for profile in profiles {
+                        match source.posts(&profile, range).await {
+                            Ok(posts) => {
+                                events.deliver(Ok(Event::Posts(posts.len()))).await?;
+                                scraped.push((profile, posts));
+                            }
+                            Err(e) => {
+                                error_tx.deliver(crate::Error::Scrape(e)).await?;
+                                return Ok(());
+                            }
+                        }
+                    }
+                    scraped
+                }
+            };
+            events.deliver(Ok(Event::PostsExhausted)).await?;
+            // checkpoint the run from now on
+            let (checkpoint, resumed) = match resumed {
+                Some((checkpoint, resumed)) => (Some(Arc::new(checkpoint)), Some(resumed)),
+                None if dry_run => (None, None),
+                None => match Checkpoint::create(&out, identity, scraped.clone()).await {
+                    Ok(checkpoint) => (Some(Arc::new(checkpoint)), None),
+                    Err(e) => {
+                        // the run goes on, only it can not be resumed
+                        error_tx.deliver(crate::Error::Checkpoint(e)).await?;
+                        (None, None)
+                    }
+                },
+            };
+            // posts browsed by the interrupted run are not browsed again
+            let mut posts = Vec::new();
+            for (profile, scraped) in scraped {
+                let profile = Arc::new(profile);
+                let platform = Ustr::from(&profile.platform);
+                let scraped = scraped
+                    .into_iter()
+                    .filter(|post| {
+                        resumed
+                            .as_ref()
+                            .is_none_or(|r| !r.browsed.contains_key(&(platform, post.id)))
+                    })
+                    .map(|post| (Arc::clone(&profile), post));
+                posts.extend(scraped);
+            }
+            // the ledger of previous runs
+            let ledger = match Ledger::open(&out).await {
+                Ok(ledger) => Arc::new(ledger),
                 Err(e) => {
-                    println!("Error creating posts {e}");
-                    error_tx.send_or_panic(crate::Error::Scrape(e)).await;
-                    return;
+                    error_tx.deliver(crate::Error::Ledger(None, e)).await?;
+                    return Ok(());
                 }
             };
-            events.send_or_panic(Ok(Event::PostsExhausted)).await;
+            // the archive is only created once the posts are known,
+            // so runs failing before leave none behind
+            let storage: Arc<dyn Storage> = match storage {
+                Some(storage) => storage,
+                None => match &pack {
+                    Some(pack) => match Archive::new(pack, &out) {
+                        Ok(archive) => Arc::new(archive),
+                        Err(e) => {
+                            error_tx.deliver(crate::Error::Storage(e)).await?;
+                            return Ok(());
+                        }
+                    },
+                    None => Arc::new(Local::default()),
+                },
+            };
+            *opened.lock().unwrap() = Some(Arc::clone(&storage));
             // collect files. each file will have two copies. one for download and one for UI.
-            let files_rx =
-                file::collect_files(posts, profile, out, format, save_text, error_tx.clone());
-            let files = listen_files(files_rx, events.clone());
+            let case_insensitive = file::is_case_insensitive(&out).await;
+            let layout = Layout {
+                out,
+                format,
+                sanitizer: sanitizer.clone(),
+                collision,
+                case_insensitive,
+                save_text,
+                save_meta,
+                dry_run,
+                storage: Arc::clone(&storage),
+            };
+            let known = Known::new(
+                &layout,
+                linked,
+                (!ignore_ledger).then(|| Arc::clone(&ledger)),
+            );
+            // files collected by the interrupted run are queued again, unless downloaded since
+            let mut recalled = Vec::new();
+            if let Some(Resumed {
+                browsed, finished, ..
+            }) = resumed
+            {
+                for ((platform, post), collected) in browsed {
+                    known.recall(&collected);
+                    let (files, mut skipped) = collected;
+                    let mut queued = Vec::new();
+                    for file in files {
+                        if finished.contains(&file.id())
+                            || storage.exists(&file.path()).await.unwrap_or(false)
+                        {
+                            skipped.push((file, Skip::Exists));
+                        } else {
+                            queued.push(file);
+                        }
+                    }
+                    if let Some(checkpoint) = &checkpoint {
+                        checkpoint.queued(queued.len());
+                    }
+                    recalled.push(Browse::Done(Browsed {
+                        platform,
+                        post,
+                        collected: (queued, skipped),
+                        text: None,
+                        meta: None,
+                        sidecars: Vec::new(),
+                    }));
+                }
+            }
+            let mut files_rx = file::collect_files(
+                posts,
+                layout,
+                known,
+                Arc::clone(&source),
+                error_tx.clone(),
+                checkpoint.clone(),
+                &ctrl,
+            );
+            if !recalled.is_empty() {
+                files_rx = replay(recalled, files_rx, &ctrl);
+            }
+            let files = listen_files(files_rx, Arc::clone(&hooks), events.clone(), dry_run, &ctrl);
+            if dry_run {
+                while files.recv().await.is_ok() {}
+                events.deliver(Ok(Event::Clear)).await?;
+                return Ok(());
+            }
             // download
-            let progress = worker::start_workers(workers, files.clone(), error_tx);
-            listen_prog(progress, events);
+            let duties = Duties {
+                ledger,
+                checkpoint: checkpoint.clone(),
+                extractor: extract.map(|extract| Arc::new(Extractor::new(extract, sanitizer))),
+                hooks: Arc::clone(&hooks),
+                storage: Arc::clone(&storage),
+            };
+            let progress =
+                worker::start_workers(workers, files.clone(), source, duties, error_tx, &ctrl);
+            // finished along with the downloads from now on
+            opened.lock().unwrap().take();
+            listen_prog(progress, storage, hooks, checkpoint, events, done, &ctrl);
+            Ok(())
+        };
+        // the tasks spawned by the run see the cancellation by themselves
+        let cancel = control.clone();
+        control.spawn(async move {
+            let res = tokio::select! {
+                res = run => res,
+                _ = cancel.cancelled() => Ok(()),
+            };
+            // runs ending before the downloads leave no half written archive behind
+            let storage = unfinished.lock().unwrap().take();
+            if let Some(storage) = storage
+                && let Err(e) = storage.finish().await
+            {
+                storage_events
+                    .deliver(Err(crate::Error::Storage(e)))
+                    .await?;
+            }
+            res
         });
-        event_rx
+        (event_rx, control)
+    }
+}
+
+impl<S: Source> Engine<S> {
+    /// Plan moving the files already downloaded into `out` to where `format` puts them.
+    ///
+    /// Nothing is written while planning.
+    pub async fn reorganize(&self) -> anyhow::Result<Reorganization> {
+        let EngineOptions {
+            out,
+            format,
+            sanitizer,
+            collision,
+            ..
+        } = self.options.clone();
+        let case_insensitive = file::is_case_insensitive(&out).await;
+        let source = Arc::clone(&self.source);
+        reorganize::plan(out, format, sanitizer, collision, case_insensitive, source).await
+    }
+
+    /// Compare the files of the artist at `url` with the ones downloaded into `out`.
+    pub async fn verify(&self) -> anyhow::Result<Audit> {
+        let EngineOptions {
+            url,
+            linked,
+            range,
+            out,
+            format,
+            sanitizer,
+            collision,
+            ..
+        } = self.options.clone();
+        let case_insensitive = file::is_case_insensitive(&out).await;
+        let layout = Layout {
+            out,
+            format,
+            sanitizer,
+            collision,
+            case_insensitive,
+            save_text: false,
+            save_meta: false,
+            dry_run: true,
+            storage: Arc::new(Local::default()),
+        };
+        let source = Arc::clone(&self.source);
+        verify::audit(&url, range, layout, linked, source).await
     }
 }
 
-fn listen_errors(errors: Receiver<crate::Error>, events: Sender<crate::Result<Event>>) {
-    tokio::spawn(async move {
+fn listen_errors(
+    errors: Receiver<crate::Error>,
+    hooks: Arc<HookRunner>,
+    events: Sender<crate::Result<Event>>,
+    control: &Control,
+) {
+    control.spawn(async move {
         while let Ok(e) = errors.recv().await {
-            events.send_or_panic(Err(e)).await;
+            hooks.error(&e);
+            events.deliver(Err(e)).await?;
         }
+        Ok(())
     });
 }
 
+/// Report the pace of the source until the run is `done`.
+fn listen_rates(
+    rates: Receiver<Pace>,
+    done: CancellationToken,
+    events: Sender<crate::Result<Event>>,
+    control: &Control,
+) {
+    control.spawn(async move {
+        loop {
+            let pace = tokio::select! {
+                pace = rates.recv() => match pace {
+                    Ok(pace) => pace,
+                    Err(_) => break,
+                },
+                _ = done.cancelled() => break,
+            };
+            let event = match pace {
+                Pace::Rate(interval) => Event::Rate(interval),
+                Pace::Retry(url, after) => Event::Retry(url, after),
+            };
+            events.deliver(Ok(event)).await?;
+        }
+        Ok(())
+    });
+}
+
+/// Send the posts browsed by an interrupted run before the ones browsed now.
+fn replay(
+    recalled: Vec<Browse>,
+    files_rx: Receiver<Browse>,
+    control: &Control,
+) -> Receiver<Browse> {
+    let (tx, rx) = async_channel::unbounded();
+    control.spawn(async move {
+        for browse in recalled {
+            tx.deliver(browse).await?;
+        }
+        while let Ok(browse) = files_rx.recv().await {
+            tx.deliver(browse).await?;
+        }
+        Ok(())
+    });
+    rx
+}
+
+/// Queue the collected files and report the browsed posts.
+///
+/// Posts with nothing to download, or all posts of a dry run, are finished as soon as
+/// they are browsed. The others are finished by the hooks, which keep track of them.
 fn listen_files(
-    files_rx: Receiver<Vec<File>>,
+    files_rx: Receiver<Browse>,
+    hooks: Arc<HookRunner>,
     events: Sender<crate::Result<Event>>,
+    dry_run: bool,
+    control: &Control,
 ) -> Receiver<File> {
     let (tx, rx) = async_channel::unbounded();
-    tokio::spawn(async move {
-        while let Ok(files) = files_rx.recv().await {
+    control.spawn(async move {
+        while let Ok(browse) = files_rx.recv().await {
+            let Browsed {
+                platform,
+                post,
+                collected: (files, skipped),
+                text,
+                meta,
+                ..
+            } = match browse {
+                Browse::Start(platform, post) => {
+                    events.deliver(Ok(Event::PostStart(platform, post))).await?;
+                    continue;
+                }
+                Browse::Done(browsed) => browsed,
+            };
+            if let Some(text) = text {
+                events.deliver(Ok(Event::Text(post, text))).await?;
+            }
+            if let Some(meta) = meta {
+                events.deliver(Ok(Event::Meta(post, meta))).await?;
+            }
+            let finished = dry_run || files.is_empty();
+            // nothing of the post is downloaded, yet it is done all the same
+            if !dry_run
+                && files.is_empty()
+                && let Some((file, _)) = skipped.first()
+            {
+                hooks.post_done(file);
+            }
+            hooks.collected(&files);
             for file in files.iter().cloned() {
-                tx.send_or_panic(file).await;
+                tx.deliver(file).await?;
+            }
+            events.deliver(Ok(Event::Files(files))).await?;
+            if !skipped.is_empty() {
+                events.deliver(Ok(Event::Skipped(skipped))).await?;
+            }
+            if finished {
+                events
+                    .deliver(Ok(Event::PostFinish(platform, post)))
+                    .await?;
             }
-            events.send_or_panic(Ok(Event::Files(files))).await;
         }
-        events.send_or_panic(Ok(Event::FilesExhausted)).await;
+        events.deliver(Ok(Event::FilesExhausted)).await?;
+        Ok(())
     });
     rx
 }
 
-fn listen_prog(prog: Receiver<(FileID, Prog)>, events: Sender<crate::Result<Event>>) {
-    tokio::spawn(async move {
+fn listen_prog(
+    prog: Receiver<(FileID, Prog)>,
+    storage: Arc<dyn Storage>,
+    hooks: Arc<HookRunner>,
+    checkpoint: Option<Arc<Checkpoint>>,
+    events: Sender<crate::Result<Event>>,
+    done: DropGuard,
+    control: &Control,
+) {
+    let ctrl = control.clone();
+    control.spawn(async move {
         while let Ok((id, prog)) = prog.recv().await {
             let event = match prog {
                 Prog::Enqueue => Event::Enqueue(id),
@@ -106,8 +565,135 @@ fn listen_prog(prog: Receiver<(FileID, Prog)>, events: Sender<crate::Result<Even
                 Prog::Chunk(size) => Event::Chunk(id, size),
                 Prog::Fin => Event::Fin(id),
             };
-            events.send_or_panic(Ok(event)).await;
+            events.deliver(Ok(event)).await?;
         }
-        events.send_or_panic(Ok(Event::Clear)).await;
+        drop(done);
+        // before the hooks of the run, which may want the archive
+        if let Err(e) = storage.finish().await {
+            events.deliver(Err(crate::Error::Storage(e))).await?;
+        }
+        hooks.finish().await;
+        if let Some(checkpoint) = checkpoint
+            && let Err(e) = checkpoint.close(ctrl.is_cancelled()).await
+        {
+            events.deliver(Err(crate::Error::Checkpoint(e))).await?;
+        }
+        events.deliver(Ok(Event::Clear)).await
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use anyhow::anyhow;
+    use bytes::Bytes;
+    use futures::{StreamExt, stream};
+    use tempfile::TempDir;
+    use yaks_common::Range;
+
+    use super::*;
+    use crate::{Attachment, Body, Content, Memory, Post, PostID, Profile};
+
+    /// An artist with a single post, kept in memory with its files.
+    struct Shelf {
+        files: HashMap<&'static str, &'static [u8]>,
+    }
+
+    impl Source for Shelf {
+        fn name(&self) -> &str {
+            "shelf"
+        }
+
+        fn parse_url<'a>(&self, url: &'a str) -> anyhow::Result<(&'a str, &'a str)> {
+            url.split_once('/')
+                .ok_or(anyhow!("Cannot parse URL `{url}`"))
+        }
+
+        fn post_url(&self, profile: &Profile, post: PostID) -> String {
+            format!("shelf/{}/{post}", profile.user_id)
+        }
+
+        async fn profile(&self, platform: &str, user_id: &str) -> anyhow::Result<Profile> {
+            Ok(Profile {
+                platform: platform.into(),
+                user_id: user_id.into(),
+                nickname: "Alice".into(),
+                username: "alice".into(),
+                post_count: 1,
+            })
+        }
+
+        async fn linked(&self, _: &Profile) -> anyhow::Result<Vec<anyhow::Result<Profile>>> {
+            Ok(Vec::new())
+        }
+
+        async fn posts(&self, _: &Profile, _: Range) -> anyhow::Result<Vec<Post>> {
+            Ok(vec![Post {
+                id: 1,
+                title: "First".into(),
+                published: None,
+                added: None,
+                edited: None,
+                unparsed: Vec::new(),
+            }])
+        }
+
+        async fn content(&self, _: &Profile, _: &Post) -> anyhow::Result<Content> {
+            let attachments = self
+                .files
+                .keys()
+                .map(|&name| Attachment {
+                    filename: name.into(),
+                    url: name.into(),
+                    hash: None,
+                })
+                .collect();
+            Ok(Content {
+                attachments,
+                ..Content::default()
+            })
+        }
+
+        async fn body(&self, file: &File) -> anyhow::Result<Body> {
+            let content = self.files[file.url.as_ref()];
+            let chunks = content.chunks(4).map(|chunk| Ok(Bytes::from_static(chunk)));
+            Ok(Body {
+                size: content.len() as u64,
+                chunks: stream::iter(chunks).boxed(),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn downloads_from_any_source() {
+        let out = TempDir::new().unwrap();
+        let shelf = Shelf {
+            files: HashMap::from([("cover.png", b"\x89PNG from memory".as_slice())]),
+        };
+        let memory = Arc::new(Memory::default());
+        let options = EngineOptions::new("shelf/1").out(out.path());
+        let engine = Engine::with_source(options, shelf)
+            .unwrap()
+            .with_storage(memory.clone())
+            .unwrap();
+
+        let (rx, control) = engine.start();
+        let mut finished = 0;
+        while let Ok(event) = rx.recv().await {
+            match event {
+                Ok(Event::Fin(_)) => finished += 1,
+                Ok(_) => {}
+                Err(e) => panic!("{e:#}"),
+            }
+        }
+        control.shutdown().await;
+        assert_eq!(finished, 1);
+        let paths = memory.paths();
+        assert_eq!(paths.len(), 1, "{paths:?}");
+        assert_eq!(
+            memory.get(&paths[0]).as_deref(),
+            Some(b"\x89PNG from memory".as_slice())
+        );
+    }
+}
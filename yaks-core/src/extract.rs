@@ -0,0 +1,422 @@
+use std::{
+    borrow::Cow,
+    fs,
+    io::{self, BufReader, Read},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::anyhow;
+use encoding_rs::SHIFT_JIS;
+use serde::{Deserialize, Serialize};
+use sevenz_rust::{Archive, Password, SevenZReader};
+use yaks_common::Sanitizer;
+use zip::ZipArchive;
+
+/// How downloaded archives are unpacked.
+///
+/// A `.zip` or `.7z` file is extracted into a folder named after it, next to it.
+/// Archives that would unpack to more than the limits allow are left alone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Extract {
+    /// Passwords tried in order on encrypted archives.
+    pub passwords: Vec<String>,
+    /// Remove the archive once it is extracted.
+    pub delete: bool,
+    /// Bytes an archive may unpack to at most.
+    pub max_size: u64,
+    /// Entries an archive may hold at most.
+    pub max_files: usize,
+    /// How many times larger than its compressed size an entry may be.
+    pub max_ratio: u64,
+}
+
+impl Default for Extract {
+    fn default() -> Self {
+        Self {
+            passwords: Vec::new(),
+            delete: false,
+            max_size: 16 << 30,
+            max_files: 100_000,
+            max_ratio: 1_000,
+        }
+    }
+}
+
+/// An archive which is beyond the limits. No password will help with it.
+#[derive(thiserror::Error, Debug)]
+#[error("{0}")]
+struct Refused(String);
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Zip,
+    SevenZ,
+}
+
+/// Extracts the archives of a run.
+pub(crate) struct Extractor {
+    conf: Extract,
+    sanitizer: Sanitizer,
+}
+
+impl Extractor {
+    pub fn new(conf: Extract, sanitizer: Sanitizer) -> Self {
+        Self { conf, sanitizer }
+    }
+
+    /// Extract the archive at `path` without blocking the runtime, if it is one.
+    pub async fn run(self: &Arc<Self>, path: PathBuf) -> anyhow::Result<Option<PathBuf>> {
+        let this = Arc::clone(self);
+        tokio::task::spawn_blocking(move || extract(&path, &this.sanitizer, &this.conf)).await?
+    }
+}
+
+/// Extract the archive at `path`, if it is one.
+/// Returns the folder it is extracted into.
+fn extract(path: &Path, sanitizer: &Sanitizer, conf: &Extract) -> anyhow::Result<Option<PathBuf>> {
+    let ext = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase());
+    let kind = match ext.as_deref() {
+        Some("zip") => Kind::Zip,
+        Some("7z") => Kind::SevenZ,
+        // RAR archives can not be extracted, which the README tells, so they are left as is
+        _ => return Ok(None),
+    };
+    let parent = path.parent().unwrap();
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    // extracted aside first, so a folder is either complete or missing
+    let tmp = parent.join(format!(".{stem}.extracting"));
+
+    let attempt = |password: Option<&str>| {
+        if tmp.exists() {
+            fs::remove_dir_all(&tmp)?;
+        }
+        fs::create_dir_all(&tmp)?;
+        let unpacker = Unpacker {
+            into: &tmp,
+            sanitizer,
+            conf,
+            left: conf.max_size,
+        };
+        match kind {
+            Kind::Zip => unpacker.zip(path, password),
+            Kind::SevenZ => unpacker.sevenz(path, password),
+        }
+    };
+    let mut res = attempt(None);
+    let mut passwords = conf.passwords.iter();
+    while let Err(e) = &res {
+        let Some(password) = passwords.next() else {
+            break;
+        };
+        if e.is::<Refused>() {
+            break;
+        }
+        res = attempt(Some(password));
+    }
+    if let Err(e) = res {
+        let _ = fs::remove_dir_all(&tmp);
+        let n = conf.passwords.len();
+        if n > 0 && !e.is::<Refused>() {
+            return Err(e.context(format!("Tried {n} passwords")));
+        }
+        return Err(e);
+    }
+
+    let into = free(parent, &stem);
+    fs::rename(&tmp, &into)?;
+    if conf.delete {
+        fs::remove_file(path)?;
+    }
+    Ok(Some(into))
+}
+
+/// The first of `stem`, `stem (1)`, `stem (2)`... that is not taken in `parent`.
+fn free(parent: &Path, stem: &str) -> PathBuf {
+    let mut into = parent.join(stem);
+    let mut n = 1;
+    while into.exists() {
+        into = parent.join(format!("{stem} ({n})"));
+        n += 1;
+    }
+    into
+}
+
+/// Writes the entries of an archive into a folder, within the limits.
+struct Unpacker<'a> {
+    into: &'a Path,
+    sanitizer: &'a Sanitizer,
+    conf: &'a Extract,
+    /// Bytes that may still be written.
+    left: u64,
+}
+
+impl Unpacker<'_> {
+    fn zip(mut self, path: &Path, password: Option<&str>) -> anyhow::Result<()> {
+        let mut zip = ZipArchive::new(BufReader::new(fs::File::open(path)?))?;
+        self.check_files(zip.len())?;
+        let mut declared = 0;
+        for i in 0..zip.len() {
+            declared += zip.by_index_raw(i)?.size();
+        }
+        self.check_size(declared)?;
+        for i in 0..zip.len() {
+            let encrypted = zip.by_index_raw(i)?.encrypted();
+            let mut entry = match (encrypted, password) {
+                (true, Some(password)) => zip.by_index_decrypt(i, password.as_bytes())?,
+                _ => zip.by_index(i)?,
+            };
+            if entry.is_symlink() {
+                continue;
+            }
+            let name = zip_name(entry.name_raw(), entry.name());
+            let Some(to) = self.entry_path(&name)? else {
+                continue;
+            };
+            if entry.is_dir() {
+                fs::create_dir_all(to)?;
+                continue;
+            }
+            self.check_ratio(&name, entry.size(), entry.compressed_size())?;
+            self.write(&mut entry, &to)?;
+        }
+        Ok(())
+    }
+
+    fn sevenz(mut self, path: &Path, password: Option<&str>) -> anyhow::Result<()> {
+        let password = password.map_or_else(Password::empty, Password::from);
+        let mut archive = SevenZReader::open(path, password).map_err(|e| anyhow!("{e}"))?;
+        let entries = &archive.archive().files;
+        self.check_files(entries.len())?;
+        self.check_size(entries.iter().map(|entry| entry.size()).sum())?;
+        self.check_blocks(archive.archive())?;
+        // errors of ours can not go through the reader, so they wait here
+        let mut failure = None;
+        let res = archive.for_each_entries(|entry, reader| {
+            let res = (|| {
+                let Some(to) = self.entry_path(entry.name())? else {
+                    return Ok(());
+                };
+                if entry.is_directory() {
+                    fs::create_dir_all(to)?;
+                    return Ok(());
+                }
+                self.write(reader, &to)
+            })();
+            match res {
+                Ok(()) => Ok(true),
+                Err(e) => {
+                    failure = Some(e);
+                    Err(sevenz_rust::Error::other("aborted"))
+                }
+            }
+        });
+        match (failure, res) {
+            (Some(e), _) => Err(e),
+            (None, Err(e)) => Err(anyhow!("{e}")),
+            (None, Ok(())) => Ok(()),
+        }
+    }
+
+    fn check_files(&self, files: usize) -> anyhow::Result<()> {
+        if files > self.conf.max_files {
+            let max = self.conf.max_files;
+            return Err(Refused(format!("The archive holds more than {max} entries")).into());
+        }
+        Ok(())
+    }
+
+    fn check_size(&self, declared: u64) -> anyhow::Result<()> {
+        if declared > self.left {
+            return Err(self.too_large().into());
+        }
+        Ok(())
+    }
+
+    fn check_ratio(&self, name: &str, size: u64, compressed: u64) -> anyhow::Result<()> {
+        if compressed > 0 && size / compressed > self.conf.max_ratio {
+            return Err(Refused(format!("`{name}` is compressed suspiciously well")).into());
+        }
+        Ok(())
+    }
+
+    /// Entries of a 7z are compressed together in blocks,
+    /// so an entry is as compressed as its block.
+    fn check_blocks(&self, archive: &Archive) -> anyhow::Result<()> {
+        let map = &archive.stream_map;
+        let mut unpacked = vec![0; archive.folders.len()];
+        for (entry, block) in archive.files.iter().zip(&map.file_folder_index) {
+            if let Some(size) = block.and_then(|block| unpacked.get_mut(block)) {
+                *size += entry.size();
+            }
+        }
+        for (entry, block) in archive.files.iter().zip(&map.file_folder_index) {
+            let Some(block) = *block else {
+                continue;
+            };
+            let first = map.folder_first_pack_stream_index[block];
+            let streams = archive.folders[block].packed_streams.len();
+            let packed = archive
+                .pack_sizes
+                .get(first..first + streams)
+                .map_or(0, |sizes| sizes.iter().sum());
+            self.check_ratio(entry.name(), unpacked[block], packed)?;
+        }
+        Ok(())
+    }
+
+    fn too_large(&self) -> Refused {
+        Refused(format!(
+            "The archive unpacks to more than {} bytes",
+            self.conf.max_size
+        ))
+    }
+
+    /// Where an entry goes, or `None` for entries that have no name.
+    ///
+    /// Names that climb out of the folder are refused.
+    fn entry_path(&self, name: &str) -> anyhow::Result<Option<PathBuf>> {
+        let mut components = Vec::new();
+        for component in name.split(['/', '\\']) {
+            match component {
+                "" | "." => {}
+                ".." => return Err(Refused(format!("`{name}` points outside")).into()),
+                component => components.push(self.sanitizer.value(component)),
+            }
+        }
+        if components.is_empty() {
+            return Ok(None);
+        }
+        let rel = self.sanitizer.path(&components.join("/"), 0);
+        let rel = Path::new(&rel);
+        if !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(Refused(format!("`{name}` points outside")).into());
+        }
+        Ok(Some(self.into.join(rel)))
+    }
+
+    fn write(&mut self, reader: &mut dyn Read, to: &Path) -> anyhow::Result<()> {
+        fs::create_dir_all(to.parent().unwrap())?;
+        let mut file = fs::File::create(to)?;
+        // one more byte than allowed tells the archive lied about its size
+        let written = io::copy(&mut reader.take(self.left + 1), &mut file)?;
+        if written > self.left {
+            return Err(self.too_large().into());
+        }
+        self.left -= written;
+        Ok(())
+    }
+}
+
+/// Zip files made on Japanese Windows name their entries in Shift_JIS without saying so.
+fn zip_name<'a>(raw: &'a [u8], decoded: &'a str) -> Cow<'a, str> {
+    if let Ok(name) = str::from_utf8(raw) {
+        return Cow::Borrowed(name);
+    }
+    SHIFT_JIS
+        .decode_without_bom_handling_and_without_replacement(raw)
+        .unwrap_or(Cow::Borrowed(decoded))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::TempDir;
+    use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+    use super::*;
+
+    fn zip(dir: &Path, entries: &[(&str, &[u8])]) -> PathBuf {
+        let path = dir.join("pack.zip");
+        let mut zip = ZipWriter::new(fs::File::create(&path).unwrap());
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        for (name, content) in entries {
+            zip.start_file(*name, options).unwrap();
+            zip.write_all(content).unwrap();
+        }
+        zip.finish().unwrap();
+        path
+    }
+
+    fn sevenz(dir: &Path, entries: &[(&str, &[u8])]) -> PathBuf {
+        let src = dir.join("src");
+        for (name, content) in entries {
+            fs::create_dir_all(src.join(name).parent().unwrap()).unwrap();
+            fs::write(src.join(name), content).unwrap();
+        }
+        let path = dir.join("pack.7z");
+        sevenz_rust::compress_to_path(&src, &path).unwrap();
+        fs::remove_dir_all(src).unwrap();
+        path
+    }
+
+    fn run(path: &Path, conf: &Extract) -> anyhow::Result<Option<PathBuf>> {
+        extract(path, &Sanitizer::default(), conf)
+    }
+
+    #[test]
+    fn extracts_next_to_the_archive() {
+        let dir = TempDir::new().unwrap();
+        let entries: &[(&str, &[u8])] = &[("a.txt", b"hello"), ("sub/b.txt", b"world")];
+        for path in [zip(dir.path(), entries), sevenz(dir.path(), entries)] {
+            let into = run(&path, &Extract::default()).unwrap().unwrap();
+            assert_eq!(fs::read(into.join("a.txt")).unwrap(), b"hello");
+            assert_eq!(fs::read(into.join("sub/b.txt")).unwrap(), b"world");
+        }
+        // the second one does not take the folder of the first
+        assert!(dir.path().join("pack (1)").is_dir());
+    }
+
+    #[test]
+    fn leaves_other_files_alone() {
+        let dir = TempDir::new().unwrap();
+        for name in ["a.png", "a.rar", "a"] {
+            let path = dir.path().join(name);
+            fs::write(&path, b"not an archive").unwrap();
+            assert!(run(&path, &Extract::default()).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn refuses_bombs() {
+        let conf = Extract {
+            max_ratio: 10,
+            ..Default::default()
+        };
+        let zeros = vec![0; 1 << 20];
+        let dir = TempDir::new().unwrap();
+        for path in [
+            zip(dir.path(), &[("zeros", &zeros)]),
+            sevenz(dir.path(), &[("zeros", &zeros)]),
+        ] {
+            let e = run(&path, &conf).unwrap_err();
+            assert!(e.is::<Refused>(), "{e}");
+        }
+        // nothing is left behind
+        let left = fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(left, 2);
+    }
+
+    #[test]
+    fn refuses_large_archives() {
+        let conf = Extract {
+            max_size: 8,
+            ..Default::default()
+        };
+        let dir = TempDir::new().unwrap();
+        let path = zip(dir.path(), &[("a", b"12345"), ("b", b"67890")]);
+        assert!(run(&path, &conf).unwrap_err().is::<Refused>());
+    }
+
+    #[test]
+    fn refuses_entries_climbing_out() {
+        let dir = TempDir::new().unwrap();
+        let path = zip(dir.path(), &[("../evil", b"evil")]);
+        assert!(run(&path, &Extract::default()).unwrap_err().is::<Refused>());
+        assert!(!dir.path().parent().unwrap().join("evil").exists());
+    }
+}
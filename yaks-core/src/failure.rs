@@ -0,0 +1,193 @@
+use std::{error::Error as _, io, path::PathBuf};
+
+use derive_more::Display;
+use reqwest::StatusCode;
+use serde::Serialize;
+
+use crate::{Error, FileID, PostID};
+
+/// What went wrong, whatever the engine was doing at the time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The server could not be reached, or the connection broke.
+    #[display("network")]
+    Network,
+    /// The server answered with an error status, like 404.
+    #[display("http {_0}")]
+    Http(u16),
+    /// The server kept answering 429 Too Many Requests.
+    #[display("rate limit")]
+    RateLimit,
+    /// A response or a file could not be understood.
+    #[display("parse")]
+    Parse,
+    /// Reading or writing on disk failed.
+    #[display("io")]
+    Io,
+    /// The disk is full.
+    #[display("disk full")]
+    DiskFull,
+    /// A file is not what the server said it would be.
+    #[display("integrity")]
+    Integrity,
+    /// A path is refused, like one outside of the output folder.
+    #[display("path")]
+    Path,
+    #[display("other")]
+    Other,
+}
+
+impl ErrorKind {
+    /// Whether trying again later may succeed.
+    pub fn is_retryable(self) -> bool {
+        match self {
+            Self::Network | Self::RateLimit | Self::Integrity => true,
+            Self::Http(status) => status == 408 || status >= 500,
+            Self::Parse | Self::Io | Self::DiskFull | Self::Path | Self::Other => false,
+        }
+    }
+}
+
+/// The server sent more or less than it announced.
+#[derive(Debug, thiserror::Error)]
+#[error("Received {received} bytes instead of {expected}")]
+pub(crate) struct Mismatch {
+    pub expected: u64,
+    pub received: u64,
+}
+
+impl Error {
+    /// What went wrong, found from the causes of the error.
+    pub fn kind(&self) -> ErrorKind {
+        let Some(e) = self.cause() else {
+            return ErrorKind::Path;
+        };
+        for cause in e.chain() {
+            if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+                // a body cut short is told as a decode error, caused by the body one
+                if e.is_decode() && e.source().is_some_and(|s| s.is::<reqwest::Error>()) {
+                    continue;
+                }
+                return match e.status() {
+                    Some(StatusCode::TOO_MANY_REQUESTS) => ErrorKind::RateLimit,
+                    Some(status) => ErrorKind::Http(status.as_u16()),
+                    None if e.is_decode() => ErrorKind::Parse,
+                    None => ErrorKind::Network,
+                };
+            }
+            if let Some(e) = cause.downcast_ref::<io::Error>() {
+                return match e.kind() {
+                    io::ErrorKind::StorageFull => ErrorKind::DiskFull,
+                    _ => ErrorKind::Io,
+                };
+            }
+            if cause.is::<serde_json::Error>() {
+                return ErrorKind::Parse;
+            }
+            if cause.is::<Mismatch>() {
+                return ErrorKind::Integrity;
+            }
+        }
+        ErrorKind::Other
+    }
+
+    /// Whether trying again later may succeed.
+    pub fn is_retryable(&self) -> bool {
+        self.kind().is_retryable()
+    }
+
+    /// What the engine was doing.
+    pub fn stage(&self) -> &'static str {
+        match self {
+            Self::Profile(_) => "profile",
+            Self::Scrape(_) => "scrape",
+            Self::Linked(_) => "linked",
+            Self::Browse(..) => "browse",
+            Self::Download(..) => "download",
+            Self::Ledger(..) => "ledger",
+            Self::Checkpoint(_) => "checkpoint",
+            Self::Storage(_) => "storage",
+            Self::Extract(..) => "extract",
+            Self::Hook(..) => "hook",
+            Self::Escape(..) => "escape",
+        }
+    }
+
+    /// The post concerned, if any.
+    pub fn post(&self) -> Option<PostID> {
+        match self {
+            Self::Browse(post, _) | Self::Escape(post, _) => Some(*post),
+            Self::Download(file, _) | Self::Extract(file, _) | Self::Ledger(Some(file), _) => {
+                Some(file.post)
+            }
+            _ => None,
+        }
+    }
+
+    /// The file concerned, if any.
+    pub fn file(&self) -> Option<FileID> {
+        match self {
+            Self::Download(file, _) | Self::Extract(file, _) | Self::Ledger(Some(file), _) => {
+                Some(*file)
+            }
+            _ => None,
+        }
+    }
+
+    /// The URL requested, if the error comes from a request.
+    pub fn url(&self) -> Option<&str> {
+        self.cause()?
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<reqwest::Error>()?.url())
+            .map(|url| url.as_str())
+    }
+
+    fn cause(&self) -> Option<&anyhow::Error> {
+        match self {
+            Self::Profile(e)
+            | Self::Scrape(e)
+            | Self::Linked(e)
+            | Self::Browse(_, e)
+            | Self::Download(_, e)
+            | Self::Ledger(_, e)
+            | Self::Checkpoint(e)
+            | Self::Storage(e)
+            | Self::Extract(_, e)
+            | Self::Hook(_, e) => Some(e),
+            Self::Escape(..) => None,
+        }
+    }
+}
+
+/// An error with its context, as reported at the end of a run.
+#[derive(Debug, Clone, Serialize)]
+pub struct Failure {
+    pub kind: ErrorKind,
+    pub retryable: bool,
+    pub stage: &'static str,
+    pub message: String,
+    pub url: Option<String>,
+    pub post: Option<PostID>,
+    pub file: Option<FileID>,
+    pub path: Option<PathBuf>,
+}
+
+impl From<&Error> for Failure {
+    fn from(e: &Error) -> Self {
+        let kind = e.kind();
+        Self {
+            kind,
+            retryable: kind.is_retryable(),
+            stage: e.stage(),
+            message: e.to_string(),
+            url: e.url().map(Into::into),
+            post: e.post(),
+            file: e.file(),
+            path: match e {
+                Error::Escape(_, path) => Some(path.clone()),
+                _ => None,
+            },
+        }
+    }
+}
@@ -1,242 +1,942 @@
 use std::{
-    path::{Path, PathBuf},
-    sync::Arc,
+    collections::{HashMap, HashSet},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
 };
 
+use anyhow::{anyhow, bail};
 use async_channel::{self, Receiver, Sender};
-use derive_more::Deref;
-use serde::Deserialize;
-use tokio::{fs, io::AsyncWriteExt};
+use chrono::NaiveDateTime;
+use derive_more::{Deref, Display};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+use tokio::{fs, sync::watch};
 use ustr::Ustr;
-use yaks_common::{ResponseExt, SenderExt, StrExt};
+use yaks_common::{MAX_COMPONENT_BYTES, Sanitizer, SenderExt, StrExt};
 
 use crate::{
-    API_BASE, BROWSE_INTERVAL, POST_BROWSERS, client,
-    post::{Post, Profile},
+    Control, PARTS_SUFFIX, POST_BROWSERS,
+    checkpoint::Checkpoint,
+    ledger::Ledger,
+    post::{Post, PostID, Profile},
+    source::{Attachment, Content, Source},
+    storage::Storage,
+    template::{Key, Template, Value},
 };
 
 /// correspond to one single file in a post
 #[derive(Debug, Clone, Deref)]
 pub struct File(Arc<FileRef>);
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct FileRef {
     pub filename: Box<str>,
     pub url: Box<str>,
     pub dest: Box<Path>,
+    pub origin: Origin,
 }
 
-pub type FileID = usize;
+/// The post a file comes from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Origin {
+    pub platform: Ustr,
+    pub user_id: Ustr,
+    pub post: PostID,
+    /// Position of the file among the ones of the post.
+    pub index: usize,
+    /// The hash of the content, if the server names the file after it.
+    pub hash: Option<Box<str>>,
+}
+
+/// Identifies a file the same way across runs and processes.
+///
+/// Written as `{platform}/{post}/{index}`, like `fanbox/123456/0`.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Display,
+    SerializeDisplay,
+    DeserializeFromStr,
+)]
+#[display("{platform}/{post}/{index}")]
+pub struct FileID {
+    pub platform: Ustr,
+    pub post: PostID,
+    pub index: usize,
+}
+
+impl FromStr for FileID {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow!("`{s}` is not an ID of file");
+        let mut split = s.split('/');
+        let (Some(platform), Some(post), Some(index), None) =
+            (split.next(), split.next(), split.next(), split.next())
+        else {
+            return Err(invalid());
+        };
+        if platform.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            platform: Ustr::from(platform),
+            post: post.parse().map_err(|_| invalid())?,
+            index: index.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Files collected from a post: the ones to download and the ones skipped.
+pub type Collected = (Vec<File>, Vec<(File, Skip)>);
+
+/// What the browsers report.
+pub(crate) enum Browse {
+    /// A post is being browsed.
+    Start(Ustr, PostID),
+    /// A post has been browsed.
+    Done(Browsed),
+}
+
+/// A browsed post: its files, and where its text and metadata have been saved.
+pub(crate) struct Browsed {
+    pub platform: Ustr,
+    pub post: PostID,
+    pub collected: Collected,
+    pub text: Option<PathBuf>,
+    pub meta: Option<PathBuf>,
+    /// Where the text and metadata of the post go, whether they are saved or not.
+    pub sidecars: Vec<PathBuf>,
+}
 
 impl File {
-    #[inline(always)]
     pub fn id(&self) -> FileID {
-        self.0.as_ref() as *const FileRef as *const () as usize
+        FileID {
+            platform: self.origin.platform,
+            post: self.origin.post,
+            index: self.origin.index,
+        }
+    }
+
+    /// A file to be saved at `dest`, downloaded into a `.parts` file next to it.
+    ///
+    /// Files overwriting each other should not share their parts, so `claimed` is
+    /// the number of files that claimed the same destination before.
+    fn new(mut dest: PathBuf, url: Box<str>, origin: Origin, claimed: usize) -> Self {
+        let filename = dest
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned()
+            .into_boxed_str();
+        dest.pop();
+        if claimed == 0 {
+            dest.push(format!("{filename}{PARTS_SUFFIX}"));
+        } else {
+            dest.push(format!("{filename}.{claimed}{PARTS_SUFFIX}"));
+        }
+        File(Arc::new(FileRef {
+            filename,
+            url,
+            dest: dest.into_boxed_path(),
+            origin,
+        }))
+    }
+
+    /// What identifies the content of the file, wherever it is saved.
+    pub(crate) fn key(&self) -> &str {
+        self.origin.hash.as_deref().unwrap_or(&self.url)
+    }
+
+    /// Where the file ends up once downloaded.
+    pub fn path(&self) -> PathBuf {
+        self.dest.parent().unwrap().join(self.filename.as_ref())
+    }
+}
+
+impl Serialize for File {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for File {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        FileRef::deserialize(deserializer).map(|file| File(Arc::new(file)))
+    }
+}
+
+/// Why a collected file is not downloaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Skip {
+    /// The destination already exists.
+    #[display("exists")]
+    Exists,
+    /// The same file has been collected from another post.
+    #[display("duplicate")]
+    Duplicate,
+    /// Another file has claimed the destination.
+    #[display("collision")]
+    Collision,
+    /// The ledger says the file has been downloaded before.
+    #[display("archived")]
+    Archived,
+}
+
+/// Where and how the content of posts is saved.
+pub struct Layout {
+    pub out: PathBuf,
+    pub format: Template,
+    pub sanitizer: Sanitizer,
+    /// What to do when two files map to the same destination.
+    pub collision: Collision,
+    /// Whether the file system of `out` ignores case.
+    pub case_insensitive: bool,
+    /// Save the text of each post as a `.md` file.
+    pub save_text: bool,
+    /// Save the metadata of each post as a `.json` file.
+    pub save_meta: bool,
+    /// Only plan the destinations. Nothing is written.
+    pub dry_run: bool,
+    /// Where the texts and metadata are written, and files are looked for.
+    pub storage: Arc<dyn Storage>,
+}
+
+/// What to do when a file maps to a destination already claimed by another file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Collision {
+    /// Append a number to the name, like `name (1).ext`.
+    #[default]
+    Number,
+    /// Keep the first file and skip the others.
+    Skip,
+    /// Download all of them. The last one finished wins.
+    Overwrite,
+}
+
+/// Destinations claimed by the files collected in this run.
+struct Claims {
+    paths: Mutex<HashMap<String, usize>>,
+    collision: Collision,
+    case_insensitive: bool,
+}
+
+impl Claims {
+    fn new(collision: Collision, case_insensitive: bool) -> Self {
+        Self {
+            paths: Mutex::new(HashMap::new()),
+            collision,
+            case_insensitive,
+        }
     }
+
+    /// Bytes to leave in the names of the files for what is appended to them.
+    ///
+    /// The `.parts` suffix always, and `.{claimed}` before it when overwriting.
+    /// Numbers are made room for when they are given, by cutting the stem.
+    fn reserved(&self) -> usize {
+        match self.collision {
+            // at most 20 digits
+            Collision::Overwrite => PARTS_SUFFIX.len() + ".18446744073709551615".len(),
+            _ => PARTS_SUFFIX.len(),
+        }
+    }
+
+    fn key(&self, path: &Path) -> String {
+        let key = path.to_string_lossy();
+        if self.case_insensitive {
+            key.to_lowercase()
+        } else {
+            key.into_owned()
+        }
+    }
+
+    /// Claim the destination for a file.
+    ///
+    /// Returns the destination to use and the number of files claimed it before,
+    /// or `None` if the file should be skipped.
+    fn claim(&self, dest: PathBuf) -> Option<(PathBuf, usize)> {
+        let mut paths = self.paths.lock().unwrap();
+        let count = paths.entry(self.key(&dest)).or_default();
+        *count += 1;
+        let claimed = *count - 1;
+        if claimed == 0 {
+            return Some((dest, 0));
+        }
+        match self.collision {
+            Collision::Skip => None,
+            Collision::Overwrite => Some((dest, claimed)),
+            Collision::Number => {
+                let stem = dest.file_stem()?.to_string_lossy().into_owned();
+                let ext = dest
+                    .extension()
+                    .map(|ext| ext.to_string_lossy().into_owned());
+                let mut n = claimed;
+                loop {
+                    let number = format!(" ({n})");
+                    let ext_len = ext.as_ref().map_or(0, |ext| ext.len() + 1);
+                    let max = MAX_COMPONENT_BYTES
+                        .saturating_sub(self.reserved() + number.len() + ext_len);
+                    let mut filename = format!("{}{number}", stem.truncate_bytes(max));
+                    if let Some(ext) = &ext {
+                        filename.push('.');
+                        filename.push_str(ext);
+                    }
+                    let numbered = dest.with_file_name(filename);
+                    // the numbered name may also be taken by a file named like that
+                    let count = paths.entry(self.key(&numbered)).or_default();
+                    if *count == 0 {
+                        *count += 1;
+                        return Some((numbered, 0));
+                    }
+                    n += 1;
+                }
+            }
+        }
+    }
+}
+
+/// What the browsers of a run know about the files collected so far.
+pub(crate) struct Known {
+    claims: Claims,
+    /// Hashes of the collected files and where they go, if duplicates are dropped.
+    seen: Option<Mutex<HashMap<Box<str>, PathBuf>>>,
+    /// Files downloaded by previous runs.
+    ledger: Option<Arc<Ledger>>,
+    turns: Turns,
+}
+
+impl Known {
+    /// With `dedup`, a file already collected from another post
+    /// (typically the same post mirrored on a linked platform) is dropped.
+    pub fn new(layout: &Layout, dedup: bool, ledger: Option<Arc<Ledger>>) -> Self {
+        Self {
+            claims: Claims::new(layout.collision, layout.case_insensitive),
+            seen: dedup.then(|| Mutex::new(HashMap::new())),
+            ledger,
+            turns: Turns::default(),
+        }
+    }
+
+    /// Learn about files collected by an interrupted run,
+    /// as if their posts had been browsed again.
+    pub fn recall(&self, (files, skipped): &Collected) {
+        let collected = files.iter().map(|file| (file, None));
+        let skipped = skipped.iter().map(|(file, skip)| (file, Some(*skip)));
+        let mut paths = self.claims.paths.lock().unwrap();
+        for (file, skip) in collected.chain(skipped) {
+            if let Some(seen) = &self.seen
+                && let Some(hash) = &file.origin.hash
+            {
+                seen.lock()
+                    .unwrap()
+                    .entry(hash.clone())
+                    .or_insert_with(|| file.path());
+            }
+            // duplicates are dropped before claiming anything
+            if skip != Some(Skip::Duplicate) {
+                *paths.entry(self.claims.key(&file.path())).or_default() += 1;
+            }
+        }
+    }
+}
+
+/// Lets the posts collect their files one after another, in the order they are listed,
+/// however fast they are browsed. Duplicates and collisions are then settled
+/// the same way on every run.
+#[derive(Default)]
+struct Turns(watch::Sender<TurnState>);
+
+#[derive(Default)]
+struct TurnState {
+    /// The post whose turn it is.
+    next: usize,
+    /// Posts done out of order, waiting for the ones before them.
+    done: HashSet<usize>,
 }
 
-pub fn collect_files(
-    posts: Vec<Post>,
-    profile: Profile,
-    out: PathBuf,
-    format: String,
-    save_text: bool,
+impl Turns {
+    async fn wait(&self, turn: usize) {
+        let mut state = self.0.subscribe();
+        // the sender lives in `self`, so waiting never fails
+        let _ = state.wait_for(|s| s.next == turn).await;
+    }
+
+    fn pass(&self, turn: usize) {
+        self.0.send_modify(|s| {
+            s.done.insert(turn);
+            while s.done.remove(&s.next) {
+                s.next += 1;
+            }
+        });
+    }
+}
+
+/// Passes the turn of a post however its browsing ends.
+struct Turn<'a>(&'a Turns, usize);
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        self.0.pass(self.1);
+    }
+}
+
+/// Probe whether the file system of `out` ignores case, without writing anything.
+///
+/// The closest existing ancestor is looked up again with its case flipped. If nothing
+/// tells, the usual file system of the platform is assumed.
+pub async fn is_case_insensitive(out: &Path) -> bool {
+    let out = std::path::absolute(out).unwrap_or_else(|_| out.to_path_buf());
+    for ancestor in out.ancestors() {
+        let Some(name) = ancestor.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let flipped = name
+            .chars()
+            .map(|c| match c.is_uppercase() {
+                true => c.to_lowercase().collect::<String>(),
+                false => c.to_uppercase().collect(),
+            })
+            .collect::<String>();
+        if flipped == name || !fs::try_exists(ancestor).await.unwrap_or(false) {
+            continue;
+        }
+        match fs::try_exists(ancestor.with_file_name(&flipped)).await {
+            Ok(false) => return false,
+            // unless there are really two of them
+            Ok(true) if !has_entry(ancestor.parent(), &flipped).await => return true,
+            _ => {}
+        }
+    }
+    cfg!(any(windows, target_os = "macos"))
+}
+
+/// Whether `dir` lists an entry named exactly `name`. Assumes so if unsure.
+async fn has_entry(dir: Option<&Path>, name: &str) -> bool {
+    let Some(dir) = dir else {
+        return true;
+    };
+    let Ok(mut entries) = fs::read_dir(dir).await else {
+        return true;
+    };
+    loop {
+        match entries.next_entry().await {
+            Ok(Some(entry)) if entry.file_name() == name => return true,
+            Ok(Some(_)) => {}
+            Ok(None) => return false,
+            Err(_) => return true,
+        }
+    }
+}
+
+/// Browse the posts and collect their files.
+///
+/// Browsed posts are recorded in the checkpoint, if any.
+pub(crate) fn collect_files<S: Source>(
+    posts: Vec<(Arc<Profile>, Post)>,
+    layout: Layout,
+    known: Known,
+    source: Arc<S>,
     errors: Sender<crate::Error>,
-) -> Receiver<Vec<File>> {
+    checkpoint: Option<Arc<Checkpoint>>,
+    control: &Control,
+) -> Receiver<Browse> {
     let (tx, rx) = async_channel::unbounded();
     // convert vec into chann (ok this is very silly)
     let (post_tx, post_rx) = async_channel::bounded(POST_BROWSERS);
-    tokio::spawn(async move {
-        for post in posts {
-            post_tx.send_or_panic(post).await;
+    control.spawn(async move {
+        for post in posts.into_iter().enumerate() {
+            post_tx.deliver(post).await?;
         }
+        Ok(())
     });
     let posts = post_rx;
 
     // browse post
-    let format: Arc<str> = format.replace("\\", "/").trim_start_matches('/').into();
-    let out: Arc<Path> = out.as_path().into();
-    let profile: Arc<Profile> = profile.into();
+    let known = Arc::new(known);
+    let layout = Arc::new(layout);
     for _ in 0..POST_BROWSERS {
         // chann
         let tx = tx.clone();
         let posts = posts.clone();
         let errors = errors.clone();
         // arg
-        let profile = Arc::clone(&profile);
-        let out = Arc::clone(&out);
-        let format = Arc::clone(&format);
-        tokio::spawn(async move {
-            while let Ok(post) = posts.recv().await {
+        let source = Arc::clone(&source);
+        let known = Arc::clone(&known);
+        let layout = Arc::clone(&layout);
+        let checkpoint = checkpoint.clone();
+        let ctrl = control.clone();
+        control.spawn(async move {
+            while ctrl.proceed().await {
+                let Ok((turn, (profile, post))) = posts.recv().await else {
+                    break;
+                };
+                let _turn = Turn(&known.turns, turn);
                 let id = post.id;
-                match browse(post, &profile, &out, &format, save_text).await {
-                    Ok(files) => {
-                        tx.send_or_panic(files).await;
+                let platform = Ustr::from(&profile.platform);
+                tx.deliver(Browse::Start(platform, id)).await?;
+                let browsed = tokio::select! {
+                    browsed = browse(&*source, post, turn, &profile, &layout, &known, &errors) => browsed,
+                    _ = ctrl.cancelled() => break,
+                };
+                match browsed {
+                    Ok(browsed) => {
+                        if let Some(checkpoint) = &checkpoint
+                            && let Err(e) =
+                                checkpoint.browsed(platform, id, &browsed.collected).await
+                        {
+                            errors.deliver(crate::Error::Checkpoint(e)).await?;
+                        }
+                        tx.deliver(Browse::Done(browsed)).await?;
                     }
                     Err(e) => {
+                        if let Some(checkpoint) = &checkpoint {
+                            checkpoint.failed();
+                        }
                         let e = crate::Error::Browse(id, e);
-                        errors.send_or_panic(e).await;
+                        errors.deliver(e).await?;
                     }
                 }
-                tokio::time::sleep(BROWSE_INTERVAL.get()).await;
             }
+            Ok(())
         });
     }
     rx
 }
 
 async fn browse(
-    Post { id, title }: Post,
-    Profile {
-        platform,
-        user_id,
-        nickname,
-        username,
+    source: &impl Source,
+    post: Post,
+    turn: usize,
+    profile: &Profile,
+    Layout {
+        out,
+        format,
+        sanitizer,
+        save_text,
+        save_meta,
+        dry_run,
+        storage,
         ..
-    }: &Profile,
-    out: &Path,
-    format: &str,
-    save_text: bool,
-) -> anyhow::Result<Vec<File>> {
-    #[derive(Debug, Deserialize)]
-    struct Payload {
-        previews: Vec<Preview>,
-        post: BrowsablePost,
-    }
-
-    #[derive(Debug, Deserialize)]
-    struct Preview {
-        #[serde(rename = "type")]
-        ty: Ustr,
-        #[serde(default, rename = "name")]
-        filename: String,
-        #[serde(default)]
-        path: String,
-        #[serde(default)]
-        server: Ustr,
+    }: &Layout,
+    known: &Known,
+    errors: &Sender<crate::Error>,
+) -> anyhow::Result<Browsed> {
+    /// Metadata of the post, saved as a sidecar.
+    #[derive(Debug, Serialize)]
+    struct Meta<'a> {
+        id: PostID,
+        title: &'a str,
+        published: Option<NaiveDateTime>,
+        added: Option<NaiveDateTime>,
+        edited: Option<NaiveDateTime>,
+        tags: &'a [String],
+        url: String,
+        files: Vec<MetaFile>,
     }
 
-    #[derive(Debug, Deserialize)]
-    struct BrowsablePost {
-        #[serde(default, rename = "content")]
-        text: String,
+    #[derive(Debug, Serialize)]
+    struct MetaFile {
+        index: usize,
+        filename: String,
+        hash: Option<String>,
+        url: Box<str>,
+        /// Relative to the output folder
+        path: PathBuf,
     }
 
-    let title = title.to_path_safe();
-    let nickname = nickname.to_path_safe();
-    let username = username.to_path_safe();
-
-    let url = format!("{API_BASE}/{platform}/user/{user_id}/post/{id}");
-    let payload = client()
-        .get(&url)
-        .send()
-        .await?
-        .error_for_status()?
-        .sneaky_json::<Payload>()
-        .await?;
+    // a date the paths need but can not have
+    for (field, date) in &post.unparsed {
+        if let Ok(key) = field.parse()
+            && format.uses(key)
+        {
+            bail!("Can not understand the {field} date `{date}` of the post");
+        }
+    }
+    let Content {
+        text,
+        tags,
+        attachments,
+    } = source.content(profile, &post).await?;
+    let Post {
+        id,
+        title,
+        published,
+        added,
+        edited,
+        ..
+    } = post;
+    let Profile {
+        platform,
+        user_id,
+        nickname,
+        username,
+        ..
+    } = profile;
+    let site = source.name();
+    let joined_tags = tags.join(", ");
+    let post_vars = |key| match key {
+        Key::Nickname => Some(Value::Str(nickname)),
+        Key::Username => Some(Value::Str(username)),
+        Key::UserId => Some(Value::Str(user_id)),
+        Key::Platform => Some(Value::Str(platform)),
+        Key::Site => Some(Value::Str(site)),
+        Key::PostId => Some(Value::Int(id)),
+        Key::Title => Some(Value::Str(&title)),
+        Key::Published => published.map(Value::Date),
+        Key::Added => added.map(Value::Date),
+        Key::Tags => Some(Value::Str(&joined_tags)),
+        Key::FileCount => Some(Value::Int(attachments.len() as u64)),
+        Key::Filename | Key::Stem | Key::Ext | Key::Hash | Key::Index => None,
+    };
+    // destination of the post-level content, like the text or the metadata
+    let sidecar = |ext: &str| sanitizer.path(&format.for_post(ext).render(sanitizer, post_vars), 0);
+    let sidecars = ["md", "json"].map(|ext| out.join(sidecar(ext))).into();
+    let post_dest = async |ext: &str| {
+        let dest = sidecar(ext);
+        let confined = confine(out, &dest).await;
+        if confined.is_none() {
+            let e = crate::Error::Escape(id, out.join(dest));
+            // a closed channel is noticed by the browser on its next send
+            let _ = errors.deliver(e).await;
+        }
+        confined
+    };
 
     // ---------------------------------------------------------
     // save the text of the post
     // ---------------------------------------------------------
-    if save_text && !payload.post.text.is_empty() {
+    let mut text_dest = None;
+    if *save_text && !*dry_run && !text.is_empty() {
         // save as markdown
-        let text = htmd::convert(&payload.post.text).unwrap_or(payload.post.text);
-        // find the last {post_id}/{title}
-        let post_id_end = format.find("{post_id}").map(|i| i + "{post_id}".len());
-        let title_end = format.find("{title}").map(|i| i + "{title}".len());
-        let end = post_id_end.into_iter().chain(title_end).max();
-
-        let format = if let Some(end) = end {
-            let append = match format[end..].chars().next() {
-                // user arrange posts into separate folders
-                Some('/') => "/post.md",
-                // user arrange posts under one big folder
-                _ => ".md",
-            };
-            let mut format = format[..end].to_string();
-            format.push_str(append);
-            format
-        } else {
-            // post-level meta is missing.
-            let mut format = format.to_string();
-            format.push_str("{post_id}_{title}.md");
-            format
-        };
-
-        let dest = format
-            .replace("{user_id}", &user_id)
-            .replace("{post_id}", &id.to_string())
-            .replace("{username}", &username)
-            .replace("{nickname}", &nickname)
-            .replace("{title}", &title);
-        let dest = out.join(dest);
-        if !fs::try_exists(&dest).await? {
-            let mut dest = {
-                let parent = dest.parent().unwrap();
-                fs::create_dir_all(parent).await?;
-                fs::File::create(dest).await?
-            };
-            dest.write_all(text.as_bytes()).await?;
+        let text = htmd::convert(&text).unwrap_or_else(|_| text.clone());
+        if let Some(dest) = post_dest("md").await
+            && !storage.exists(&dest).await?
+        {
+            storage.write(&dest, text.as_bytes()).await?;
+            text_dest = Some(dest);
         }
     }
 
     // ---------------------------------------------------------
     // collect the files
     // ---------------------------------------------------------
+    known.turns.wait(turn).await;
     let mut files = Vec::new();
+    let mut skipped = Vec::new();
+    let mut meta_files = Vec::new();
     for (
         index,
-        Preview {
+        Attachment {
             filename,
-            path,
-            server,
-            ..
+            url,
+            hash,
         },
-    ) in payload
-        .previews
-        .into_iter()
-        .filter(|p| p.ty == "thumbnail")
-        .enumerate()
+    ) in attachments.iter().enumerate()
     {
-        let filename = filename.to_path_safe();
-        let url = format!("{server}/data{path}").into_boxed_str();
-        let mut format = format.to_string();
-        if !format.ends_with("{filename}")
-            && let Some(ext) = Path::new(filename.as_ref()).extension()
+        let url: Box<str> = url.as_str().into();
+        let stem = Path::new(filename).file_stem().map(|s| s.to_string_lossy());
+        let ext = Path::new(filename).extension().map(|e| e.to_string_lossy());
+        let file_vars = |key| match key {
+            Key::Filename => Some(Value::Str(filename)),
+            Key::Stem => stem.as_deref().map(Value::Str),
+            Key::Ext => ext.as_deref().map(Value::Str),
+            Key::Hash => hash.as_deref().map(Value::Str),
+            Key::Index => Some(Value::Int(index as u64)),
+            key => post_vars(key),
+        };
+        let mut dest = format.render(sanitizer, file_vars);
+        if !format.ends_with(&[Key::Filename, Key::Ext])
+            && let Some(ext) = &ext
         {
-            format.push('.');
-            format.push_str(ext.to_string_lossy().as_ref());
-        }
-        // todo use runtime formatting library
-        let dest = format
-            .replace("{user_id}", &user_id)
-            .replace("{post_id}", &id.to_string())
-            .replace("{index}", &index.to_string())
-            .replace("{username}", &username)
-            .replace("{nickname}", &nickname)
-            .replace("{title}", &title)
-            .replace("{filename}", &filename);
-
-        let mut dest = out.join(&dest);
-        if fs::try_exists(&dest).await? {
+            dest.push('.');
+            dest.push_str(&sanitizer.value(ext));
+        }
+        let dest = sanitizer.path(&dest, known.claims.reserved());
+        let Some(dest) = confine(out, &dest).await else {
+            let e = crate::Error::Escape(id, out.join(dest));
+            errors.deliver(e).await?;
+            continue;
+        };
+        let origin = Origin {
+            platform: Ustr::from(platform),
+            user_id: Ustr::from(user_id),
+            post: id,
+            index,
+            hash: hash.as_deref().map(Into::into),
+        };
+        let meta_file = |path: &Path| -> anyhow::Result<MetaFile> {
+            Ok(MetaFile {
+                index,
+                filename: filename.clone(),
+                hash: hash.clone(),
+                url: url.clone(),
+                path: path.strip_prefix(out)?.to_path_buf(),
+            })
+        };
+        if let Some(seen) = &known.seen
+            && let Some(hash) = &hash
+            && let Some(kept) = seen.lock().unwrap().get(hash.as_str())
+        {
+            // the post holds the file all the same, only kept elsewhere
+            if *save_meta {
+                meta_files.push(meta_file(kept)?);
+            }
+            skipped.push((File::new(dest, url, origin, 0), Skip::Duplicate));
             continue;
         }
-        let filename = dest
-            .file_name()
-            .unwrap()
-            .to_string_lossy()
-            .into_owned()
-            .into_boxed_str();
-        // append .part to dest files for recovery
-        dest.pop();
-        dest.push(format!("{filename}.parts"));
-        let dest = dest.into_boxed_path();
-        let file = File(Arc::new(FileRef {
-            filename,
-            url,
-            dest,
-        }));
-        files.push(file);
+        let Some((dest, claimed)) = known.claims.claim(dest.clone()) else {
+            skipped.push((File::new(dest, url, origin, 0), Skip::Collision));
+            continue;
+        };
+        if let Some(seen) = &known.seen
+            && let Some(hash) = &hash
+        {
+            seen.lock()
+                .unwrap()
+                .insert(hash.as_str().into(), dest.clone());
+        }
+
+        if *save_meta {
+            meta_files.push(meta_file(&dest)?);
+        }
+        let exists = storage.exists(&dest).await?;
+        let file = File::new(dest, url, origin, claimed);
+        if exists {
+            skipped.push((file, Skip::Exists));
+        } else if let Some(ledger) = &known.ledger
+            && ledger.contains(&file)
+        {
+            skipped.push((file, Skip::Archived));
+        } else {
+            files.push(file);
+        }
+    }
+
+    // ---------------------------------------------------------
+    // save the metadata of the post
+    // ---------------------------------------------------------
+    let mut meta_dest = None;
+    if *save_meta && !*dry_run {
+        let meta = Meta {
+            id,
+            title: &title,
+            published,
+            added,
+            edited,
+            tags: &tags,
+            url: source.post_url(profile, id),
+            files: meta_files,
+        };
+        if let Some(dest) = post_dest("json").await {
+            storage
+                .write(&dest, &serde_json::to_vec_pretty(&meta)?)
+                .await?;
+            meta_dest = Some(dest);
+        }
+    }
+    Ok(Browsed {
+        platform: Ustr::from(platform),
+        post: id,
+        collected: (files, skipped),
+        text: text_dest,
+        meta: meta_dest,
+        sidecars,
+    })
+}
+
+/// Join the relative path onto `out`, refusing anything that would end up outside of it,
+/// either through `..`, absolute components or symlinks.
+async fn confine(out: &Path, rel: &str) -> Option<PathBuf> {
+    let mut dest = out.to_path_buf();
+    let mut depth = 0;
+    for component in Path::new(rel).components() {
+        match component {
+            Component::Normal(component) => {
+                dest.push(component);
+                depth += 1;
+            }
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    if depth == 0 {
+        return None;
+    }
+    // symlinks among the existing ancestors may point anywhere
+    let root = fs::canonicalize(out).await.ok();
+    let mut ancestor = out.to_path_buf();
+    for component in dest.strip_prefix(out).ok()?.components() {
+        ancestor.push(component);
+        match fs::symlink_metadata(&ancestor).await {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                let target = fs::canonicalize(&ancestor).await.ok()?;
+                if !target.starts_with(root.as_ref()?) {
+                    return None;
+                }
+            }
+            Ok(_) => {}
+            // the rest does not exist yet
+            Err(_) => break,
+        }
+    }
+    Some(dest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claim(claims: &Claims, name: &str) -> Option<(PathBuf, usize)> {
+        claims.claim(Path::new("out").join(name))
+    }
+
+    fn name(path: &Path) -> String {
+        path.file_name().unwrap().to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn numbers_collisions() {
+        let claims = Claims::new(Collision::Number, false);
+        assert_eq!(name(&claim(&claims, "a.png").unwrap().0), "a.png");
+        assert_eq!(name(&claim(&claims, "a.png").unwrap().0), "a (1).png");
+        // taken by a file named like that
+        assert_eq!(name(&claim(&claims, "a (2).png").unwrap().0), "a (2).png");
+        assert_eq!(name(&claim(&claims, "a.png").unwrap().0), "a (3).png");
+        assert_eq!(name(&claim(&claims, "A.png").unwrap().0), "A.png");
+
+        let claims = Claims::new(Collision::Number, true);
+        claim(&claims, "a.png");
+        assert_eq!(name(&claim(&claims, "A.png").unwrap().0), "A (1).png");
+    }
+
+    #[test]
+    fn skips_or_overwrites_collisions() {
+        let claims = Claims::new(Collision::Skip, false);
+        assert!(claim(&claims, "a.png").is_some());
+        assert!(claim(&claims, "a.png").is_none());
+
+        let claims = Claims::new(Collision::Overwrite, false);
+        assert_eq!(claim(&claims, "a.png").unwrap().1, 0);
+        let (dest, claimed) = claim(&claims, "a.png").unwrap();
+        assert_eq!((name(&dest).as_str(), claimed), ("a.png", 1));
+    }
+
+    #[test]
+    fn keeps_suffixed_names_short() {
+        let sanitizer = Sanitizer::default();
+        let long = format!("{}.png", "a".repeat(300));
+        for collision in [Collision::Number, Collision::Overwrite] {
+            let claims = Claims::new(collision, false);
+            let dest = PathBuf::from(sanitizer.path(&long, claims.reserved()));
+            for _ in 0..12 {
+                let (dest, claimed) = claims.claim(dest.clone()).unwrap();
+                let file = File::new(dest, "".into(), origin(), claimed);
+                assert!(file.filename.len() <= MAX_COMPONENT_BYTES);
+                assert!(name(&file.dest).len() <= MAX_COMPONENT_BYTES);
+                assert!(file.filename.ends_with(".png"));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn confines_paths() {
+        let out = tempfile::TempDir::new().unwrap();
+        let out = out.path();
+        assert_eq!(confine(out, "a/b.png").await, Some(out.join("a/b.png")));
+        assert_eq!(confine(out, "./a/./b.png").await, Some(out.join("a/b.png")));
+        for rel in [
+            "",
+            ".",
+            "./",
+            "../a.png",
+            "a/../../b.png",
+            "a/..",
+            "/etc/passwd",
+        ] {
+            assert_eq!(confine(out, rel).await, None, "{rel}");
+        }
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn refuses_prefixes() {
+        let out = tempfile::TempDir::new().unwrap();
+        for rel in [r"C:\a.png", r"C:a.png", r"\\server\share\a.png"] {
+            assert_eq!(confine(out.path(), rel).await, None, "{rel}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn refuses_symlinks_out() {
+        use std::os::unix::fs::symlink;
+
+        let root = tempfile::TempDir::new().unwrap();
+        let out = root.path().join("out");
+        let outside = root.path().join("outside");
+        std::fs::create_dir_all(out.join("inside")).unwrap();
+        std::fs::create_dir(&outside).unwrap();
+        symlink(&outside, out.join("away")).unwrap();
+        symlink(out.join("inside"), out.join("around")).unwrap();
+
+        assert_eq!(confine(&out, "away/a.png").await, None);
+        assert_eq!(confine(&out, "away").await, None);
+        // pointing within the output folder is fine
+        assert_eq!(
+            confine(&out, "around/a.png").await,
+            Some(out.join("around/a.png"))
+        );
+        // a file linked out is refused too
+        symlink(outside.join("a.png"), out.join("inside/a.png")).unwrap();
+        std::fs::write(outside.join("a.png"), b"").unwrap();
+        assert_eq!(confine(&out, "inside/a.png").await, None);
+        // so is a link to nowhere, which would be created out
+        symlink(outside.join("gone"), out.join("gone")).unwrap();
+        assert_eq!(confine(&out, "gone/a.png").await, None);
+    }
+
+    #[tokio::test]
+    async fn probes_case_without_writing() {
+        let root = tempfile::TempDir::new().unwrap();
+        let out = root.path().join("Out");
+        std::fs::create_dir(&out).unwrap();
+        let insensitive = is_case_insensitive(&out.join("not/yet")).await;
+        assert_eq!(
+            insensitive,
+            std::fs::exists(root.path().join("oUT")).unwrap()
+        );
+        assert_eq!(std::fs::read_dir(&out).unwrap().count(), 0);
+        assert_eq!(std::fs::read_dir(root.path()).unwrap().count(), 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn tells_apart_two_folders_named_alike() {
+        let root = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir(root.path().join("out")).unwrap();
+        std::fs::create_dir(root.path().join("OUT")).unwrap();
+        // the ancestor above them tells instead
+        let insensitive = is_case_insensitive(&root.path().join("out")).await;
+        assert!(!insensitive);
+    }
+
+    fn origin() -> Origin {
+        Origin {
+            platform: Ustr::from("fanbox"),
+            user_id: Ustr::from("1"),
+            post: 1,
+            index: 0,
+            hash: None,
+        }
     }
-    Ok(files)
 }
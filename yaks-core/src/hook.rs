@@ -0,0 +1,393 @@
+use std::{
+    collections::HashMap,
+    process::Stdio,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, ensure};
+use async_channel::Sender;
+use serde::{Deserialize, Serialize};
+use tokio::{process::Command, sync::Semaphore, time};
+use tokio_util::task::TaskTracker;
+use ustr::Ustr;
+use yaks_common::SenderExt;
+
+use crate::{Control, Event, file::File, post::PostID};
+
+/// External commands run as the engine gets things done.
+///
+/// A command is split into arguments like a shell would, then the placeholders
+/// in each argument are replaced. The values are never parsed by a shell,
+/// so a title can not inject commands. They are also available as environment
+/// variables like `YAKS_PATH`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Hooks {
+    /// Run after a file is downloaded.
+    /// Offers `{path}`, `{dir}`, `{filename}`, `{url}`, `{hash}`, `{post_id}`, `{platform}` and `{user_id}`.
+    pub on_file: Option<String>,
+    /// Run after all files of a post are done, downloaded, failed or skipped.
+    /// Offers `{post_id}`, `{platform}`, `{user_id}` and `{dir}`.
+    pub on_post: Option<String>,
+    /// Run after all files are done. Offers `{out}`, `{files}` and `{errors}`.
+    pub on_run: Option<String>,
+    /// Run after something fails. Offers `{error}`.
+    pub on_error: Option<String>,
+    /// How many hooks can run at once.
+    pub jobs: usize,
+    /// Seconds before a hook is killed.
+    pub timeout: u64,
+}
+
+impl Hooks {
+    /// Make sure every command can be split, before a run fails it for each file.
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        let commands = [
+            ("on_file", &self.on_file),
+            ("on_post", &self.on_post),
+            ("on_run", &self.on_run),
+            ("on_error", &self.on_error),
+        ];
+        for (name, command) in commands {
+            if let Some(command) = command {
+                let args = split(command).map_err(|e| anyhow!("Hook `{name}`: {e}"))?;
+                ensure!(!args.is_empty(), "Hook `{name}`: The command is empty");
+            }
+        }
+        ensure!(self.jobs > 0, "At least one hook must be able to run");
+        ensure!(self.timeout > 0, "Hooks need at least a second to run");
+        Ok(())
+    }
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Self {
+            on_file: None,
+            on_post: None,
+            on_run: None,
+            on_error: None,
+            jobs: 2,
+            timeout: 300,
+        }
+    }
+}
+
+/// Runs the hooks of a run and keeps track of the posts, reporting the finished ones.
+pub(crate) struct HookRunner {
+    hooks: Hooks,
+    out: String,
+    permits: Arc<Semaphore>,
+    /// The hooks spawned, running or waiting for a permit.
+    running: TaskTracker,
+    /// Files of each post that are not done yet.
+    pending: Mutex<HashMap<(Ustr, PostID), usize>>,
+    downloaded: AtomicUsize,
+    failed: AtomicUsize,
+    /// Failed hooks and finished posts are reported as events directly.
+    /// The listener of errors holds the runner, so the errors of the run would never end.
+    events: Sender<crate::Result<Event>>,
+    control: Control,
+}
+
+type Vars = Vec<(&'static str, String)>;
+
+impl HookRunner {
+    pub fn new(
+        hooks: Hooks,
+        out: String,
+        events: Sender<crate::Result<Event>>,
+        control: Control,
+    ) -> Self {
+        let permits = Arc::new(Semaphore::new(hooks.jobs.max(1)));
+        Self {
+            hooks,
+            out,
+            permits,
+            running: TaskTracker::new(),
+            pending: Mutex::new(HashMap::new()),
+            downloaded: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+            events,
+            control,
+        }
+    }
+
+    /// Files of a post are queued.
+    pub fn collected(&self, files: &[File]) {
+        let mut pending = self.pending.lock().unwrap();
+        for file in files {
+            *pending.entry(post_of(file)).or_default() += 1;
+        }
+    }
+
+    /// A file has been downloaded.
+    pub async fn file_done(&self, file: &File) {
+        self.downloaded.fetch_add(1, Ordering::Relaxed);
+        if let Some(command) = &self.hooks.on_file {
+            let path = file.path();
+            let vars = vec![
+                ("path", path.to_string_lossy().into_owned()),
+                ("dir", path.parent().unwrap().to_string_lossy().into_owned()),
+                ("filename", file.filename.to_string()),
+                ("url", file.url.to_string()),
+                (
+                    "hash",
+                    file.origin.hash.as_deref().unwrap_or_default().into(),
+                ),
+                ("post_id", file.origin.post.to_string()),
+                ("platform", file.origin.platform.to_string()),
+                ("user_id", file.origin.user_id.to_string()),
+            ];
+            self.spawn("on_file", command, vars);
+        }
+        self.post_progress(file).await;
+    }
+
+    /// A file has failed to download.
+    pub async fn file_failed(&self, file: &File) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+        self.post_progress(file).await;
+    }
+
+    async fn post_progress(&self, file: &File) {
+        let post = post_of(file);
+        let done = {
+            let mut pending = self.pending.lock().unwrap();
+            let left = pending.entry(post).or_default();
+            *left = left.saturating_sub(1);
+            *left == 0
+        };
+        if done {
+            // a closed channel is noticed by the worker on its next send
+            let _ = self
+                .events
+                .deliver(Ok(Event::PostFinish(post.0, post.1)))
+                .await;
+            self.post_done(file);
+        }
+    }
+
+    /// All files of the post of `file` are done.
+    ///
+    /// Called directly for posts whose files are all skipped, which are never downloaded.
+    pub fn post_done(&self, file: &File) {
+        if let Some(command) = &self.hooks.on_post {
+            let dir = file.path().parent().unwrap().to_string_lossy().into_owned();
+            let vars = vec![
+                ("post_id", file.origin.post.to_string()),
+                ("platform", file.origin.platform.to_string()),
+                ("user_id", file.origin.user_id.to_string()),
+                ("dir", dir),
+            ];
+            self.spawn("on_post", command, vars);
+        }
+    }
+
+    /// Something has failed.
+    pub fn error(&self, e: &crate::Error) {
+        // failing hooks should not cause more hooks
+        if matches!(e, crate::Error::Hook(..)) {
+            return;
+        }
+        if let Some(command) = &self.hooks.on_error {
+            let vars = vec![("error", e.to_string())];
+            self.spawn("on_error", command, vars);
+        }
+    }
+
+    /// All files are done. Wait for the hooks spawned so far, then run the last one.
+    pub async fn finish(&self) {
+        // nothing waits for the hooks of the errors from now on
+        self.running.close();
+        self.running.wait().await;
+        if let Some(command) = &self.hooks.on_run {
+            let vars = vec![
+                ("out", self.out.clone()),
+                ("files", self.downloaded.load(Ordering::Relaxed).to_string()),
+                ("errors", self.failed.load(Ordering::Relaxed).to_string()),
+            ];
+            self.spawn("on_run", command, vars);
+            self.running.wait().await;
+        }
+    }
+
+    /// Run the command once a permit is available. Waits for neither,
+    /// so the workers are never held up by slow hooks.
+    fn spawn(&self, name: &'static str, command: &str, vars: Vars) {
+        let permits = Arc::clone(&self.permits);
+        let timeout = Duration::from_secs(self.hooks.timeout);
+        let command = command.to_string();
+        let events = self.events.clone();
+        self.control.spawn(self.running.track_future(async move {
+            // the semaphore is never closed
+            let permit = permits.acquire_owned().await.unwrap();
+            let res = run(name, &command, &vars, timeout).await;
+            drop(permit);
+            match res {
+                Ok(()) => Ok(()),
+                Err(e) => events.deliver(Err(crate::Error::Hook(name, e))).await,
+            }
+        }));
+    }
+}
+
+fn post_of(file: &File) -> (Ustr, PostID) {
+    (file.origin.platform, file.origin.post)
+}
+
+async fn run(name: &str, command: &str, vars: &Vars, timeout: Duration) -> anyhow::Result<()> {
+    let args = split(command)?
+        .into_iter()
+        .map(|arg| substitute(&arg, vars))
+        .collect::<Vec<_>>();
+    let Some((program, args)) = args.split_first() else {
+        bail!("The command is empty");
+    };
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .env("YAKS_HOOK", name)
+        .envs(
+            vars.iter()
+                .map(|(key, value)| (format!("YAKS_{}", key.to_uppercase()), value)),
+        )
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    let output = time::timeout(timeout, command.output())
+        .await
+        .map_err(|_| anyhow!("Timed out after {}s", timeout.as_secs()))??;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match stderr.lines().rfind(|line| !line.trim().is_empty()) {
+            Some(line) => bail!("{} ({})", output.status, line.trim()),
+            None => bail!("{}", output.status),
+        }
+    }
+    Ok(())
+}
+
+/// Split a command into arguments by whitespaces, respecting quotes and backslashes.
+fn split(command: &str) -> anyhow::Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut arg = None::<String>;
+    let mut quote = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"') | None, '\\') => {
+                let escaped = chars.next().ok_or(anyhow!("Trailing backslash"))?;
+                arg.get_or_insert_default().push(escaped);
+            }
+            (Some(_), c) => arg.get_or_insert_default().push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                arg.get_or_insert_default();
+            }
+            (None, c) if c.is_whitespace() => args.extend(arg.take()),
+            (None, c) => arg.get_or_insert_default().push(c),
+        }
+    }
+    if quote.is_some() {
+        bail!("Unclosed quote");
+    }
+    args.extend(arg);
+    Ok(args)
+}
+
+/// Replace the placeholders like `{path}` in the argument. Unknown ones are kept.
+///
+/// Values are never scanned for placeholders themselves.
+fn substitute(arg: &str, vars: &Vars) -> String {
+    let mut res = String::with_capacity(arg.len());
+    let mut rest = arg;
+    while let Some(start) = rest.find('{') {
+        res.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            let key = &rest[1..end];
+            let (_, value) = vars.iter().find(|(k, _)| *k == key)?;
+            Some((value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                res.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                res.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    res.push_str(rest);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(command: &str) -> Vec<String> {
+        split(command).unwrap()
+    }
+
+    #[test]
+    fn splits_like_a_shell() {
+        assert_eq!(args("  cp  {path}\t/backup "), ["cp", "{path}", "/backup"]);
+        assert_eq!(args(r#"echo "a b" 'c d'"#), ["echo", "a b", "c d"]);
+        assert_eq!(args(r#"echo a"b c"d"#), ["echo", "ab cd"]);
+        assert_eq!(args(r#"echo "" ''"#), ["echo", "", ""]);
+        assert!(args("").is_empty());
+    }
+
+    #[test]
+    fn escapes_with_backslashes() {
+        assert_eq!(args(r"echo a\ b"), ["echo", "a b"]);
+        assert_eq!(args(r#"echo "a \"b\"""#), ["echo", r#"a "b""#]);
+        // nothing is escaped within single quotes
+        assert_eq!(args(r"echo 'a\b'"), ["echo", r"a\b"]);
+    }
+
+    #[test]
+    fn refuses_unfinished_commands() {
+        assert!(split(r#"echo "a"#).is_err());
+        assert!(split("echo 'a").is_err());
+        assert!(split(r"echo a\").is_err());
+    }
+
+    #[test]
+    fn validates_every_command() {
+        let hooks = |on_post: &str| Hooks {
+            on_file: Some("cp {path} /backup".into()),
+            on_post: Some(on_post.into()),
+            ..Hooks::default()
+        };
+        assert!(hooks("echo {dir}").validate().is_ok());
+        assert!(hooks(r#"echo "{dir}"#).validate().is_err());
+        assert!(hooks("  ").validate().is_err());
+        assert!(Hooks::default().validate().is_ok());
+    }
+
+    #[test]
+    fn substitutes_placeholders() {
+        let vars = vec![
+            ("path", "/out/a.png".to_string()),
+            ("title", "{path}".to_string()),
+        ];
+        assert_eq!(substitute("{path}.bak", &vars), "/out/a.png.bak");
+        assert_eq!(substitute("--out={path}", &vars), "--out=/out/a.png");
+        // values are not scanned again
+        assert_eq!(substitute("{title}", &vars), "{path}");
+        // unknown or unclosed ones are kept
+        assert_eq!(substitute("{unknown} {path", &vars), "{unknown} {path");
+        assert_eq!(substitute("{{path}}", &vars), "{/out/a.png}");
+    }
+}
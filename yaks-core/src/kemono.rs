@@ -0,0 +1,274 @@
+use std::{fmt, path::Path};
+
+use anyhow::{anyhow, bail};
+use async_channel::Receiver;
+use futures::{StreamExt, TryStreamExt};
+use serde::Deserialize;
+use ustr::Ustr;
+use yaks_common::{Range, ResponseExt};
+
+use crate::{
+    API_PATH, CachePolicy, File, PACE_BACKLOG, PAGE_SIZE, Retry, SITE_NAME,
+    api::Api,
+    client,
+    post::{Post, PostID, Profile},
+    rate::{Pace, RateLimiter},
+    source::{Attachment, Body, Content, Source},
+};
+
+/// The API of Kemono, or of any site serving the same one.
+pub struct Kemono {
+    site: String,
+    api_base: String,
+    api: Api,
+    rates: Receiver<Pace>,
+}
+
+impl Kemono {
+    /// Ask the site at `site`, like `https://kemono.cr`.
+    pub fn new(site: &str, cache: CachePolicy, retry: Retry) -> Self {
+        let site = site.trim_end_matches('/').to_string();
+        // nobody may be listening, so only the latest paces are kept
+        let (rate_tx, rates) = async_channel::bounded(PACE_BACKLOG);
+        Self {
+            api_base: format!("{site}{API_PATH}"),
+            site,
+            api: Api::new(RateLimiter::new(rate_tx, retry), cache),
+            rates,
+        }
+    }
+}
+
+impl fmt::Debug for Kemono {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Kemono")
+            .field("site", &self.site)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Source for Kemono {
+    fn name(&self) -> &str {
+        SITE_NAME
+    }
+
+    fn parse_url<'a>(&self, url: &'a str) -> anyhow::Result<(&'a str, &'a str)> {
+        parse_url(url)
+    }
+
+    fn post_url(&self, profile: &Profile, post: PostID) -> String {
+        let Profile {
+            platform, user_id, ..
+        } = profile;
+        format!("{}/{platform}/user/{user_id}/post/{post}", self.site)
+    }
+
+    async fn profile(&self, platform: &str, user_id: &str) -> anyhow::Result<Profile> {
+        #[derive(Debug, Deserialize)]
+        struct Payload {
+            #[serde(rename = "name")]
+            pub nickname: String,
+            #[allow(unused)]
+            #[serde(rename = "public_id")]
+            pub username: String,
+            pub post_count: usize,
+        }
+
+        let Payload {
+            nickname,
+            username,
+            post_count,
+        } = self
+            .api
+            .get(&format!(
+                "{}/{platform}/user/{user_id}/profile",
+                self.api_base
+            ))
+            .await?
+            .sneaky_json::<Payload>()
+            .await?;
+
+        let platform = platform.to_string();
+        let user_id = user_id.to_string();
+        let profile = Profile {
+            platform,
+            user_id,
+            nickname,
+            username,
+            post_count,
+        };
+        Ok(profile)
+    }
+
+    async fn linked(&self, profile: &Profile) -> anyhow::Result<Vec<anyhow::Result<Profile>>> {
+        #[derive(Debug, Deserialize)]
+        struct Link {
+            #[serde(rename = "service")]
+            platform: String,
+            #[serde(rename = "id")]
+            user_id: String,
+        }
+
+        let Profile {
+            platform,
+            user_id,
+            nickname,
+            ..
+        } = profile;
+        let links = self
+            .api
+            .get(&format!(
+                "{}/{platform}/user/{user_id}/links",
+                self.api_base
+            ))
+            .await?
+            .sneaky_json::<Vec<Link>>()
+            .await?;
+
+        let mut res = Vec::new();
+        for Link { platform, user_id } in links {
+            let linked = self
+                .profile(&platform, &user_id)
+                .await
+                .map(|linked| Profile {
+                    nickname: nickname.clone(),
+                    ..linked
+                });
+            res.push(linked);
+        }
+        Ok(res)
+    }
+
+    async fn posts(&self, profile: &Profile, range: Range) -> anyhow::Result<Vec<Post>> {
+        let Profile {
+            platform,
+            user_id,
+            post_count,
+            ..
+        } = profile;
+        let mut res = Vec::new();
+        let mut offset = 0;
+        'quit: loop {
+            let url = format!(
+                "{}/{platform}/user/{user_id}/posts?o={offset}",
+                self.api_base
+            );
+            let posts: Vec<Post> = self.api.get(&url).await?.sneaky_json().await?;
+
+            for post in posts {
+                if post.id > range {
+                    continue;
+                }
+                if post.id < range {
+                    break 'quit;
+                }
+                res.push(post);
+            }
+            offset += PAGE_SIZE;
+            if offset > *post_count {
+                break;
+            }
+        }
+        Ok(res)
+    }
+
+    async fn content(&self, profile: &Profile, post: &Post) -> anyhow::Result<Content> {
+        #[derive(Debug, Deserialize)]
+        struct Payload {
+            previews: Vec<Preview>,
+            post: BrowsablePost,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct Preview {
+            #[serde(rename = "type")]
+            ty: Ustr,
+            #[serde(default, rename = "name")]
+            filename: String,
+            #[serde(default)]
+            path: String,
+            #[serde(default)]
+            server: Ustr,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct BrowsablePost {
+            #[serde(default, rename = "content")]
+            text: String,
+            tags: Option<Vec<String>>,
+        }
+
+        let Profile {
+            platform, user_id, ..
+        } = profile;
+        let url = format!(
+            "{}/{platform}/user/{user_id}/post/{}",
+            self.api_base, post.id
+        );
+        let Payload { previews, post } = self.api.get(&url).await?.sneaky_json::<Payload>().await?;
+
+        let attachments = previews
+            .into_iter()
+            .filter(|p| p.ty == "thumbnail")
+            .map(
+                |Preview {
+                     filename,
+                     path,
+                     server,
+                     ..
+                 }| Attachment {
+                    // the stem of the path is the hash of the file
+                    hash: Path::new(&path)
+                        .file_stem()
+                        .map(|hash| hash.to_string_lossy().into_owned()),
+                    url: format!("{server}/data{path}"),
+                    filename,
+                },
+            )
+            .collect();
+        Ok(Content {
+            text: post.text,
+            tags: post.tags.unwrap_or_default(),
+            attachments,
+        })
+    }
+
+    async fn body(&self, file: &File) -> anyhow::Result<Body> {
+        let resp = client()
+            .get(file.url.as_ref())
+            .send()
+            .await?
+            .error_for_status()?;
+        let size = resp
+            .content_length()
+            .ok_or(anyhow!("content-length is missing"))?;
+        let chunks = resp.bytes_stream().map_err(anyhow::Error::from).boxed();
+        Ok(Body { size, chunks })
+    }
+
+    fn rates(&self) -> Option<Receiver<Pace>> {
+        Some(self.rates.clone())
+    }
+}
+
+/// Split `https://kemono.cr/{platform}/user/{user_id}` or `{platform}/{user_id}`.
+fn parse_url(url: &str) -> anyhow::Result<(&str, &str)> {
+    let split = url
+        .split("?")
+        .next()
+        .unwrap()
+        .split("/")
+        .collect::<Vec<_>>();
+    let (platform, user_id) = if split.len() == 2 {
+        (split[0], split[1])
+    } else {
+        let Some(index) = split.iter().copied().position(|s| s == "user") else {
+            bail!("Cannot parse URL `{}`", url);
+        };
+        if index >= split.len() {
+            bail!("Cannot parse URL `{}`", url);
+        }
+        (split[index - 1], split[index + 1])
+    };
+    Ok((platform, user_id))
+}
@@ -0,0 +1,128 @@
+use std::{
+    collections::HashSet,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+};
+use ustr::Ustr;
+
+use crate::{
+    LEDGER_NAME,
+    file::{File, FileID},
+    post::PostID,
+};
+
+/// A file that has been fully downloaded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub platform: Ustr,
+    pub user_id: Ustr,
+    pub post: PostID,
+    /// Position of the file in its post. Missing from records of older versions.
+    #[serde(default)]
+    pub index: Option<usize>,
+    pub hash: Option<Box<str>>,
+    pub url: Box<str>,
+    pub size: u64,
+    /// Relative to the output folder.
+    pub path: PathBuf,
+}
+
+impl Record {
+    /// What identifies the content of the file, wherever it is saved.
+    pub(crate) fn key(&self) -> &str {
+        self.hash.as_deref().unwrap_or(&self.url)
+    }
+
+    /// The ID of the recorded file, if the record knows its index.
+    pub fn id(&self) -> Option<FileID> {
+        Some(FileID {
+            platform: self.platform,
+            post: self.post,
+            index: self.index?,
+        })
+    }
+}
+
+/// Append-only record of every file downloaded into the output folder,
+/// saved as JSON lines in its root.
+///
+/// Files in the ledger are recognised as archived even after being moved or deleted.
+pub struct Ledger {
+    out: PathBuf,
+    archived: HashSet<Box<str>>,
+    lock: Mutex<()>,
+}
+
+impl Ledger {
+    pub async fn open(out: &Path) -> anyhow::Result<Self> {
+        let archived = Self::records(out)
+            .await?
+            .iter()
+            .map(|record| record.key().into())
+            .collect();
+        Ok(Self {
+            out: out.to_path_buf(),
+            archived,
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// Whether the file has been downloaded by a previous run.
+    pub fn contains(&self, file: &File) -> bool {
+        self.archived.contains(file.key())
+    }
+
+    /// Append a downloaded file of the given size.
+    pub async fn record(&self, file: &File, size: u64) -> anyhow::Result<()> {
+        let path = file.path();
+        let record = Record {
+            platform: file.origin.platform,
+            user_id: file.origin.user_id,
+            post: file.origin.post,
+            index: Some(file.origin.index),
+            hash: file.origin.hash.clone(),
+            url: file.url.clone(),
+            size,
+            path: path.strip_prefix(&self.out).unwrap_or(&path).to_path_buf(),
+        };
+        self.append(&record).await
+    }
+
+    /// Append a record, like a file being moved.
+    pub async fn append(&self, record: &Record) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        // lines from concurrent workers must not interleave
+        let _guard = self.lock.lock().await;
+        let mut ledger = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.out.join(LEDGER_NAME))
+            .await?;
+        ledger.write_all(line.as_bytes()).await?;
+        ledger.flush().await?;
+        Ok(())
+    }
+
+    /// Read all records of the ledger in `out`. Lines that can not be parsed,
+    /// like the last one of an interrupted run, are ignored.
+    pub async fn records(out: &Path) -> anyhow::Result<Vec<Record>> {
+        let ledger = match fs::read_to_string(out.join(LEDGER_NAME)).await {
+            Ok(ledger) => ledger,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let records = ledger
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect();
+        Ok(records)
+    }
+}
@@ -1,32 +1,78 @@
-use std::{result, sync::OnceLock, time::Duration};
+use std::{path::PathBuf, result, sync::OnceLock, time::Duration};
 
 use reqwest::{
     Client, ClientBuilder,
     header::{HeaderMap, HeaderValue},
 };
 
-mod conf;
+mod api;
+mod cache;
+mod checkpoint;
+mod control;
 mod engine;
+mod extract;
+mod failure;
 mod file;
+mod hook;
+mod kemono;
+mod ledger;
+mod options;
 mod post;
+mod rate;
+mod reorganize;
+mod report;
+mod source;
+mod storage;
+mod template;
+mod verify;
 mod worker;
 
 // re-exports
-pub use conf::Conf;
+pub use cache::CachePolicy;
+pub use control::Control;
 pub use engine::Engine;
-pub use file::{File, FileID};
+pub use extract::Extract;
+pub use failure::{ErrorKind, Failure};
+pub use file::{Collision, File, FileID, Origin, Skip};
+pub use hook::Hooks;
+pub use kemono::Kemono;
+pub use ledger::{Ledger, Record};
+pub use options::{DEFAULT_FORMAT, EngineOptions};
 pub use post::{Post, PostID, Profile};
+pub use rate::{Pace, Retry};
+pub use reorganize::{Move, Reorganization};
+pub use report::{RunReport, Stats};
+pub use source::{Attachment, Body, Content, Source};
+pub use storage::{Archive, Local, Memory, Pack, Storage};
+pub use template::Template;
+use ustr::Ustr;
+pub use verify::Audit;
 use yaks_common::RandomDuration;
 
 // consts
-pub(crate) const API_BASE: &str = "https://kemono.cr/api/v1";
+pub(crate) const SITE_NAME: &str = "kemono";
+/// Site downloaded from when none is given.
+pub const SITE_BASE: &str = "https://kemono.cr";
+pub(crate) const API_PATH: &str = "/api/v1";
 pub(crate) const PAGE_SIZE: usize = 50;
 pub(crate) const TIMEOUT: Duration = Duration::from_secs(30);
-pub(crate) const SCRAPE_INTERVAL: Duration = Duration::from_millis(500);
-pub(crate) const BROWSE_INTERVAL: RandomDuration = RandomDuration::from_millis(4000..4500);
-pub(crate) const BROWSE_RETRY_AFTER: Duration = Duration::from_secs(120);
-pub(crate) const BROWSE_RETRY_TIMES: u8 = 3;
+pub(crate) const RATE_INTERVAL: Duration = Duration::from_millis(1000);
+pub(crate) const RATE_INTERVAL_MIN: Duration = Duration::from_millis(500);
+pub(crate) const RATE_INTERVAL_MAX: Duration = Duration::from_secs(60);
+pub(crate) const RATE_JITTER: RandomDuration = RandomDuration::from_millis(0..250);
+pub(crate) const RATE_SPEEDUP_AFTER: u32 = 20;
+pub(crate) const PACE_BACKLOG: usize = 16;
+pub(crate) const RETRY_AFTER: Duration = Duration::from_secs(120);
+pub(crate) const RETRY_AFTER_MAX: Duration = Duration::from_secs(600);
+pub(crate) const RETRY_TIMES: u8 = 3;
 pub(crate) const POST_BROWSERS: usize = 5;
+pub(crate) const STATS_INTERVAL: Duration = Duration::from_secs(1);
+pub(crate) const PARTS_SUFFIX: &str = ".parts";
+pub(crate) const LEDGER_NAME: &str = ".yaks-ledger.jsonl";
+pub(crate) const JOURNAL_NAME: &str = ".yaks-undo.jsonl";
+pub(crate) const CHECKPOINT_NAME: &str = ".yaks-run.jsonl";
+pub(crate) const REPORT_NAME: &str = ".yaks-report.json";
+pub(crate) const SPOOL_NAME: &str = ".yaks-spool";
 
 // static
 pub(crate) fn client() -> &'static Client {
@@ -67,8 +113,16 @@ pub enum Event {
     Posts(usize),
     /// All pages are handled. No more post to offer.
     PostsExhausted,
+    /// A post is being browsed.
+    PostStart(Ustr, PostID),
+    /// The text of a post has been saved at the given path.
+    Text(PostID, PathBuf),
+    /// The metadata of a post has been saved at the given path.
+    Meta(PostID, PathBuf),
     /// Files from a post are collected.
     Files(Vec<File>),
+    /// Files from a post are collected but will not be downloaded.
+    Skipped(Vec<(File, Skip)>),
     /// All posts are browsed. No more file to collect.
     FilesExhausted,
     /// A file is added to the download queue.
@@ -81,13 +135,25 @@ pub enum Event {
     Chunk(FileID, u64),
     /// A file has been fully downloaded.
     Fin(FileID),
+    /// All files of a post are done, downloaded or not.
+    PostFinish(Ustr, PostID),
     /// All files are downloaded.
     Clear,
+    /// The interval between two API requests has been adjusted.
+    Rate(Duration),
+    /// A request rejected by the API is retried after the given delay.
+    Retry(Box<str>, Duration),
+    /// The counts of the run have changed. Sent at most once in a while.
+    Stats(Stats),
+    /// Summary of the run, sent right before `Clear`,
+    /// or last when the run stops early.
+    Report(RunReport),
 }
 
 /// Possible errors, which may carry extra metadata.
 ///
 /// The metadata is not included in the displayed String.
+/// What went wrong is told by `kind`, whatever the variant.
 ///
 /// UI should decide how to represent the metadata within
 #[derive(thiserror::Error, Debug)]
@@ -96,8 +162,32 @@ pub enum Error {
     Profile(anyhow::Error),
     #[error(transparent)]
     Scrape(anyhow::Error),
+    /// An account linked to the artist can not be loaded.
+    /// The artist and their other accounts are downloaded all the same.
+    #[error("Failed to fetch a linked account ({0})")]
+    Linked(anyhow::Error),
     #[error("{1}")]
     Browse(PostID, anyhow::Error),
     #[error("{1}")]
     Download(FileID, anyhow::Error),
+    /// The ledger of the output folder can not be read,
+    /// or the given file, downloaded all the same, can not be recorded in it.
+    #[error("Failed to {verb} the ledger ({})", .1, verb = if .0.is_some() { "write" } else { "read" })]
+    Ledger(Option<FileID>, anyhow::Error),
+    /// The checkpoint of the run can not be resumed or written.
+    #[error("Failed to checkpoint the run ({0})")]
+    Checkpoint(anyhow::Error),
+    /// The storage can not be started or finished,
+    /// like an archive not created or left incomplete.
+    #[error("Failed to write the files ({0})")]
+    Storage(anyhow::Error),
+    /// A downloaded archive can not be extracted.
+    #[error("Failed to extract the archive ({1})")]
+    Extract(FileID, anyhow::Error),
+    /// A hook has failed or timed out.
+    #[error("Hook `{0}` failed ({1})")]
+    Hook(&'static str, anyhow::Error),
+    /// A destination would end up outside of the output folder.
+    #[error("Refused to write `{}` outside of the output folder", .1.display())]
+    Escape(PostID, PathBuf),
 }
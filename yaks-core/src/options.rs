@@ -0,0 +1,328 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, ensure};
+use serde::{Deserialize, Serialize};
+use serde_with::{DisplayFromStr, serde_as};
+use yaks_common::{Range, Sanitizer};
+
+use crate::{CachePolicy, Collision, Extract, Hooks, Pack, Retry, SITE_BASE, Template};
+
+/// Filename format used when none is given.
+pub const DEFAULT_FORMAT: &str = "{nickname}/{post_id}_{index}";
+
+/// Everything a run needs to know.
+///
+/// Built with chained setters, or deserialized from a conf file like `conf.toml`,
+/// whose keys are accepted as aliases. Missing fields fall back to their defaults.
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EngineOptions {
+    // sources
+    /// URL of the artist. Also accepts the format `{platform}/{user_id}`.
+    pub url: String,
+    /// Also download from the accounts linked to the artist on other platforms.
+    pub linked: bool,
+
+    // filters
+    /// Inclusive range of IDs of posts.
+    pub range: Range,
+    /// Download files even if the ledger says they have been downloaded before.
+    pub ignore_ledger: bool,
+
+    // naming
+    /// Where the files are saved.
+    pub out: PathBuf,
+    /// Pack the files into an archive instead of saving them into `out`.
+    /// The ledger, the checkpoint and the report are still kept in `out`.
+    pub pack: Option<Pack>,
+    /// Where each file goes within `out`.
+    #[serde_as(as = "DisplayFromStr")]
+    pub format: Template,
+    /// How titles, filenames and such are turned into paths.
+    /// Its `policy` and `nfc` are read at the top level.
+    #[serde(flatten)]
+    pub sanitizer: Sanitizer,
+    /// What to do when two files map to the same destination.
+    #[serde(alias = "on_collision")]
+    pub collision: Collision,
+    /// Save the textual content of each post.
+    pub save_text: bool,
+    /// Save the metadata of each post as a `.json` file next to its text.
+    pub save_meta: bool,
+
+    // network
+    /// Base URL of the site serving the Kemono API.
+    pub site: String,
+    /// How many files are downloaded at once.
+    #[serde(alias = "jobs")]
+    pub workers: u8,
+    /// How responses of the API are cached between runs.
+    /// Seconds alone, like `cache_ttl`, are revalidated after that long.
+    #[serde(alias = "cache_ttl")]
+    pub cache: CachePolicy,
+    /// How requests rejected by the API are retried.
+    pub retry: Retry,
+
+    // after download
+    /// Commands run as files, posts and the run are done.
+    pub hooks: Hooks,
+    /// Extract downloaded archives into folders next to them.
+    pub extract: Option<Extract>,
+    /// Only plan the files. Nothing is written nor downloaded.
+    pub dry_run: bool,
+    /// Continue the run interrupted in `out`, if any, without scraping
+    /// nor browsing again what it had done.
+    pub resume: bool,
+}
+
+impl Default for EngineOptions {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            linked: false,
+            range: Range::default(),
+            ignore_ledger: false,
+            out: dirs_next::download_dir().unwrap_or_default(),
+            pack: None,
+            format: DEFAULT_FORMAT.parse().unwrap(),
+            sanitizer: Sanitizer::default(),
+            collision: Collision::default(),
+            save_text: false,
+            save_meta: false,
+            site: SITE_BASE.to_string(),
+            workers: 5,
+            cache: CachePolicy::default(),
+            retry: Retry::default(),
+            hooks: Hooks::default(),
+            extract: None,
+            dry_run: false,
+            resume: false,
+        }
+    }
+}
+
+impl EngineOptions {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            ..Default::default()
+        }
+    }
+
+    /// The options saved in `yaks/conf.toml` under the config folder,
+    /// or the defaults if there is none.
+    pub async fn load() -> anyhow::Result<Self> {
+        let conf_path = dirs_next::config_dir()
+            .ok_or(anyhow!("Can not locate conf path."))?
+            .join("yaks")
+            .join("conf.toml");
+        if !conf_path.try_exists()? {
+            return Ok(Self::default());
+        }
+        let conf_str = tokio::fs::read_to_string(conf_path).await?;
+        let options = toml::from_str(&conf_str)?;
+        Ok(options)
+    }
+
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.url = url.into();
+        self
+    }
+
+    pub fn linked(mut self, linked: bool) -> Self {
+        self.linked = linked;
+        self
+    }
+
+    pub fn range(mut self, range: Range) -> Self {
+        self.range = range;
+        self
+    }
+
+    pub fn ignore_ledger(mut self, ignore_ledger: bool) -> Self {
+        self.ignore_ledger = ignore_ledger;
+        self
+    }
+
+    pub fn out(mut self, out: impl Into<PathBuf>) -> Self {
+        self.out = out.into();
+        self
+    }
+
+    pub fn pack(mut self, pack: Option<Pack>) -> Self {
+        self.pack = pack;
+        self
+    }
+
+    pub fn format(mut self, format: Template) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn sanitizer(mut self, sanitizer: Sanitizer) -> Self {
+        self.sanitizer = sanitizer;
+        self
+    }
+
+    pub fn collision(mut self, collision: Collision) -> Self {
+        self.collision = collision;
+        self
+    }
+
+    pub fn save_text(mut self, save_text: bool) -> Self {
+        self.save_text = save_text;
+        self
+    }
+
+    pub fn save_meta(mut self, save_meta: bool) -> Self {
+        self.save_meta = save_meta;
+        self
+    }
+
+    pub fn site(mut self, site: impl Into<String>) -> Self {
+        self.site = site.into();
+        self
+    }
+
+    pub fn workers(mut self, workers: u8) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    pub fn cache(mut self, cache: CachePolicy) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    pub fn retry(mut self, retry: Retry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    pub fn extract(mut self, extract: Option<Extract>) -> Self {
+        self.extract = extract;
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Check the options before a run starts with them.
+    ///
+    /// The URL is left to the source, which knows what it looks like.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.range.start > self.range.end {
+            bail!("The range `{}` is empty", self.range);
+        }
+        if self.out.as_os_str().is_empty() {
+            bail!("Can not locate the default download folder");
+        }
+        if self.pack.is_some() {
+            ensure!(!self.dry_run, "Nothing is packed in a dry run");
+            // the archive of the interrupted run would be replaced
+            ensure!(!self.resume, "A packed run can not be resumed");
+            ensure!(
+                self.extract.is_none(),
+                "Archives can only be extracted from files on disk"
+            );
+        }
+        self.sanitizer.policy.validate()?;
+        ensure!(self.workers > 0, "At least one worker is needed");
+        self.hooks.validate()?;
+        if let Some(extract) = &self.extract {
+            ensure!(
+                extract.max_size > 0 && extract.max_files > 0 && extract.max_ratio > 0,
+                "Limits of extraction must be positive"
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use yaks_common::Policy;
+
+    use super::*;
+
+    fn conf(toml: &str) -> EngineOptions {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn reads_the_keys_of_the_conf() {
+        let options = conf(
+            r#"
+            site = "https://kemono.cr"
+            out = "/some/other/place"
+            format = "{username}/{title}/{filename}"
+            jobs = 16
+            cache_ttl = 3600
+            replace = { ":" = "-", "?" = "" }
+            nfc = true
+            on_collision = "skip"
+
+            [retry]
+            times = 3
+            "#,
+        );
+        assert_eq!(options.site, "https://kemono.cr");
+        assert_eq!(options.out, PathBuf::from("/some/other/place"));
+        assert_eq!(options.workers, 16);
+        assert_eq!(
+            options.cache,
+            CachePolicy::Revalidate(Duration::from_secs(3600))
+        );
+        assert_eq!(options.sanitizer.policy.replacement(':'), Some("-"));
+        assert_eq!(options.sanitizer.policy.replacement('?'), Some(""));
+        assert!(options.sanitizer.nfc);
+        assert_eq!(options.collision, Collision::Skip);
+        assert_eq!(options.retry.times, 3);
+        // the others keep their defaults
+        assert_eq!(options.hooks.jobs, Hooks::default().jobs);
+        assert!(options.extract.is_none());
+        options.validate().unwrap();
+    }
+
+    #[test]
+    fn reads_the_fields_too() {
+        let options = conf(
+            r#"
+            workers = 2
+            cache = "offline"
+            policy = "posix"
+            collision = "overwrite"
+            "#,
+        );
+        assert_eq!(options.workers, 2);
+        assert_eq!(options.cache, CachePolicy::Offline);
+        assert!(matches!(options.sanitizer.policy, Policy::Posix));
+        assert_eq!(options.collision, Collision::Overwrite);
+        assert!(matches!(
+            conf(r#"sanitize = "posix""#).sanitizer.policy,
+            Policy::Posix
+        ));
+        assert_eq!(conf("").workers, EngineOptions::default().workers);
+    }
+
+    #[test]
+    fn refuses_unknown_policies() {
+        assert!(toml::from_str::<EngineOptions>(r#"sanitize = "ntfs""#).is_err());
+        assert!(toml::from_str::<EngineOptions>(r#"on_collision = "rename""#).is_err());
+    }
+}
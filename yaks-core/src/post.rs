@@ -1,34 +1,8 @@
-use anyhow::bail;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime};
+use serde::{Deserialize, Serialize};
+use serde_with::{DisplayFromStr, PickFirst, serde_as};
 
-use reqwest::StatusCode;
-use serde::Deserialize;
-use serde_with::{DisplayFromStr, serde_as};
-use yaks_common::{Range, ResponseExt};
-
-use crate::{API_BASE, BROWSE_RETRY_AFTER, BROWSE_RETRY_TIMES, PAGE_SIZE, SCRAPE_INTERVAL, client};
-
-pub fn parse_url(url: &str) -> anyhow::Result<(&str, &str)> {
-    let split = url
-        .split("?")
-        .next()
-        .unwrap()
-        .split("/")
-        .collect::<Vec<_>>();
-    let (platform, user_id) = if split.len() == 2 {
-        (split[0], split[1])
-    } else {
-        let Some(index) = split.iter().copied().position(|s| s == "user") else {
-            bail!("Cannot parse URL `{}`", url);
-        };
-        if index >= split.len() {
-            bail!("Cannot parse URL `{}`", url);
-        }
-        (split[index - 1], split[index + 1])
-    };
-    Ok((platform, user_id))
-}
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Profile {
     pub platform: String,
     pub user_id: String,
@@ -37,94 +11,123 @@ pub struct Profile {
     pub post_count: usize,
 }
 
-/// Get the username of the artist
-pub async fn fetch_profile(platform: &str, user_id: &str) -> anyhow::Result<Profile> {
-    #[derive(Debug, Deserialize)]
-    struct Payload {
-        #[serde(rename = "name")]
-        pub nickname: String,
-        #[allow(unused)]
-        #[serde(rename = "public_id")]
-        pub username: String,
-        pub post_count: usize,
-    }
-
-    let Payload {
-        nickname,
-        username,
-        post_count,
-    } = client()
-        .get(format!("{API_BASE}/{platform}/user/{user_id}/profile"))
-        .send()
-        .await?
-        .error_for_status()?
-        .sneaky_json::<Payload>()
-        .await?;
-
-    let platform = platform.to_string().into();
-    let user_id = user_id.to_string().into();
-    let profile = Profile {
-        platform,
-        user_id,
-        nickname,
-        username,
-        post_count,
-    };
-    Ok(profile)
-}
-
-#[serde_as]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "RawPost")]
 pub struct Post {
-    #[serde_as(as = "DisplayFromStr")]
     pub id: PostID,
     pub title: String,
+    pub published: Option<NaiveDateTime>,
+    /// When the post was imported into Kemono.
+    pub added: Option<NaiveDateTime>,
+    pub edited: Option<NaiveDateTime>,
+    /// Dates that can not be understood, by field, which are left empty.
+    /// Refused by the posts whose paths use them, or they would silently vanish.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unparsed: Vec<(String, String)>,
 }
 
 pub type PostID = u64;
 
-pub async fn scrape_posts(
-    platform: &str,
-    user_id: &str,
-    post_count: usize,
-    range: Range,
-) -> anyhow::Result<Vec<Post>> {
-    let mut res = Vec::new();
-    let mut offset = 0;
-    'quit: loop {
-        let url = format!("{API_BASE}/{platform}/user/{user_id}/posts?o={offset}");
-        let posts: Vec<Post> = {
-            let mut retry = 0;
-            loop {
-                let resp = client().get(&url).send().await?;
-                if resp.status() == StatusCode::TOO_MANY_REQUESTS {
-                    if retry >= BROWSE_RETRY_TIMES {
-                        break resp.error_for_status()?.sneaky_json().await?;
-                    } else {
-                        retry += 1;
-                        tokio::time::sleep(BROWSE_RETRY_AFTER).await;
-                        continue;
-                    }
-                } else {
-                    break resp.error_for_status()?.sneaky_json().await?;
-                };
-            }
-        };
+/// A post as given by the API, its dates not parsed yet.
+#[serde_as]
+#[derive(Deserialize)]
+struct RawPost {
+    // a string from the API, a number from checkpoints
+    #[serde_as(as = "PickFirst<(DisplayFromStr, _)>")]
+    id: PostID,
+    title: String,
+    #[serde(default)]
+    published: Option<String>,
+    #[serde(default)]
+    added: Option<String>,
+    #[serde(default)]
+    edited: Option<String>,
+    #[serde(default)]
+    unparsed: Vec<(String, String)>,
+}
 
-        for post in posts {
-            if post.id > range {
-                continue;
+impl From<RawPost> for Post {
+    fn from(raw: RawPost) -> Self {
+        let mut unparsed = raw.unparsed;
+        let mut date = |field: &str, date: Option<String>| {
+            let date = date?;
+            let parsed = parse_date(&date);
+            if parsed.is_none() {
+                unparsed.push((field.into(), date));
             }
-            if post.id < range {
-                break 'quit;
-            }
-            res.push(post);
-        }
-        offset += PAGE_SIZE;
-        if offset > post_count {
-            break;
+            parsed
+        };
+        Self {
+            id: raw.id,
+            title: raw.title,
+            published: date("published", raw.published),
+            added: date("added", raw.added),
+            edited: date("edited", raw.edited),
+            unparsed,
         }
-        tokio::time::sleep(SCRAPE_INTERVAL).await;
     }
-    Ok(res)
+}
+
+/// Dates from the API are in ISO 8601, with or without the time, in UTC if an offset is given.
+fn parse_date(date: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S%.f")
+        .or_else(|_| NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S%.f"))
+        .or_else(|_| DateTime::parse_from_rfc3339(date).map(|d| d.naive_utc()))
+        .or_else(|_| {
+            NaiveDate::parse_from_str(date, "%Y-%m-%d").map(|d| d.and_time(NaiveTime::MIN))
+        })
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(date: &str) -> Post {
+        let json = format!(r#"{{"id": "1", "title": "", "published": {date}}}"#);
+        serde_json::from_str(&json).unwrap()
+    }
+
+    fn published(date: &str) -> Option<NaiveDateTime> {
+        post(date).published
+    }
+
+    fn date(y: i32, m: u32, d: u32, h: u32, min: u32, s: u32) -> Option<NaiveDateTime> {
+        NaiveDate::from_ymd_opt(y, m, d)?.and_hms_opt(h, min, s)
+    }
+
+    #[test]
+    fn parses_dates() {
+        let noon = date(2024, 5, 1, 12, 0, 0);
+        assert_eq!(published(r#""2024-05-01T12:00:00""#), noon);
+        assert_eq!(published(r#""2024-05-01 12:00:00""#), noon);
+        assert_eq!(published(r#""2024-05-01T14:00:00+02:00""#), noon);
+        assert_eq!(published(r#""2024-05-01""#), date(2024, 5, 1, 0, 0, 0));
+        assert_eq!(published("null"), None);
+        assert!(post("null").unparsed.is_empty());
+    }
+
+    #[test]
+    fn keeps_garbage_aside() {
+        let post = post(r#""last tuesday""#);
+        assert_eq!(post.published, None);
+        assert_eq!(
+            post.unparsed,
+            [("published".to_string(), "last tuesday".to_string())]
+        );
+        // as saved in a checkpoint
+        let json = serde_json::to_string(&post).unwrap();
+        let post: Post = serde_json::from_str(&json).unwrap();
+        assert_eq!(post.published, None);
+        assert_eq!(post.unparsed.len(), 1);
+    }
+
+    #[test]
+    fn keeps_parsed_dates_through_checkpoints() {
+        let post = post(r#""2024-05-01T14:00:00+02:00""#);
+        let json = serde_json::to_string(&post).unwrap();
+        let post: Post = serde_json::from_str(&json).unwrap();
+        assert_eq!(post.published, date(2024, 5, 1, 12, 0, 0));
+        assert!(post.unparsed.is_empty());
+    }
 }
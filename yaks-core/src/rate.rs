@@ -0,0 +1,212 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use async_channel::Sender;
+use reqwest::{
+    Response, StatusCode,
+    header::{self, HeaderMap},
+};
+use serde::{Deserialize, Serialize};
+use serde_with::{DurationSeconds, serde_as};
+use tokio::time::Instant;
+
+use crate::{
+    RATE_INTERVAL, RATE_INTERVAL_MAX, RATE_INTERVAL_MIN, RATE_JITTER, RATE_SPEEDUP_AFTER,
+    RETRY_AFTER, RETRY_AFTER_MAX, RETRY_TIMES, client,
+};
+
+/// The one and only gate to the API.
+///
+/// Every request to the API waits for its slot here. The interval between
+/// slots is doubled whenever the server complains with 429 and is slowly
+/// reduced again after a streak of successful requests.
+///
+/// The interval is reported into the `rates` sender every time it changes,
+/// and so is every retry. The oldest reports are dropped when the sender is full.
+pub struct RateLimiter {
+    state: Mutex<State>,
+    rates: Sender<Pace>,
+    retry: Retry,
+}
+
+/// What the rate limiter reports.
+#[derive(Debug)]
+pub enum Pace {
+    /// The interval between two requests has changed.
+    Rate(Duration),
+    /// The request to the URL is retried after the delay.
+    Retry(Box<str>, Duration),
+}
+
+/// How requests rejected with 429 are retried.
+#[serde_as]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Retry {
+    /// How many times a request is retried before giving up.
+    pub times: u8,
+    /// Seconds to wait when the server does not say how long.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub after: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            times: RETRY_TIMES,
+            after: RETRY_AFTER,
+        }
+    }
+}
+
+struct State {
+    interval: Duration,
+    next: Instant,
+    streak: u32,
+}
+
+impl RateLimiter {
+    pub fn new(rates: Sender<Pace>, retry: Retry) -> Self {
+        let state = State {
+            interval: RATE_INTERVAL,
+            next: Instant::now(),
+            streak: 0,
+        };
+        Self {
+            state: Mutex::new(state),
+            rates,
+            retry,
+        }
+    }
+
+    /// Send a GET request to the API once a slot is available.
+    ///
+    /// Requests rejected with 429 are retried after the period the server
+    /// asked for (or `retry.after` if it didn't).
+    pub async fn get(&self, url: &str, headers: HeaderMap) -> anyhow::Result<Response> {
+        let mut retry = 0;
+        loop {
+            self.acquire().await;
+            let resp = client().get(url).headers(headers.clone()).send().await?;
+            let status = resp.status();
+            if status != StatusCode::TOO_MANY_REQUESTS {
+                // only answers the server was happy to give count as a good streak
+                if status.is_success() || status == StatusCode::NOT_MODIFIED {
+                    self.speed_up();
+                }
+                return Ok(resp.error_for_status()?);
+            }
+            let after = retry_after(resp.headers(), SystemTime::now()).unwrap_or(self.retry.after);
+            self.slow_down(after);
+            if retry >= self.retry.times {
+                return Ok(resp.error_for_status()?);
+            }
+            retry += 1;
+            // only informative, like the rate
+            let _ = self.rates.force_send(Pace::Retry(url.into(), after));
+        }
+    }
+
+    /// Wait until the next slot.
+    async fn acquire(&self) {
+        let slot = {
+            let mut state = self.state.lock().unwrap();
+            let slot = state.next.max(Instant::now());
+            state.next = slot + state.interval + RATE_JITTER.get();
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+
+    fn speed_up(&self) {
+        let interval = {
+            let mut state = self.state.lock().unwrap();
+            state.streak += 1;
+            if state.streak < RATE_SPEEDUP_AFTER || state.interval <= RATE_INTERVAL_MIN {
+                return;
+            }
+            state.streak = 0;
+            state.interval = (state.interval * 3 / 4).max(RATE_INTERVAL_MIN);
+            state.interval
+        };
+        // the rate is only informative, so nobody may be listening
+        let _ = self.rates.force_send(Pace::Rate(interval));
+    }
+
+    fn slow_down(&self, after: Duration) {
+        let interval = {
+            let mut state = self.state.lock().unwrap();
+            let resume = Instant::now() + after;
+            state.next = state.next.max(resume);
+            state.streak = 0;
+            state.interval = (state.interval * 2).min(RATE_INTERVAL_MAX);
+            state.interval
+        };
+        // the rate is only informative, so nobody may be listening
+        let _ = self.rates.force_send(Pace::Rate(interval));
+    }
+}
+
+/// Read the `Retry-After` header, which is either in seconds or a HTTP date.
+///
+/// Never more than `RETRY_AFTER_MAX`, so a server asking for a day does not stall the run.
+fn retry_after(headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?;
+    let after = match value.trim().parse() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => {
+            let date = httpdate::parse_http_date(value).ok()?;
+            // a date in the past means now
+            date.duration_since(now).unwrap_or_default()
+        }
+    };
+    Some(after.min(RETRY_AFTER_MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    fn after(value: &str) -> Option<Duration> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RETRY_AFTER, HeaderValue::from_str(value).unwrap());
+        let now = httpdate::parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        retry_after(&headers, now)
+    }
+
+    #[test]
+    fn reads_seconds() {
+        assert_eq!(after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(after(" 5 "), Some(Duration::from_secs(5)));
+        assert_eq!(after("0"), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn reads_dates() {
+        assert_eq!(
+            after("Sun, 06 Nov 1994 08:50:07 GMT"),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(after("Sun, 06 Nov 1994 08:00:00 GMT"), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn caps_long_waits() {
+        assert_eq!(after("86400"), Some(RETRY_AFTER_MAX));
+        assert_eq!(
+            after("Mon, 07 Nov 1994 08:49:37 GMT"),
+            Some(RETRY_AFTER_MAX)
+        );
+    }
+
+    #[test]
+    fn ignores_garbage() {
+        assert_eq!(after("soon"), None);
+        assert_eq!(after("-1"), None);
+        assert_eq!(retry_after(&HeaderMap::new(), SystemTime::now()), None);
+    }
+}
@@ -0,0 +1,594 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    io::ErrorKind,
+    iter,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::AsyncWriteExt};
+use ustr::Ustr;
+use yaks_common::{Range, Sanitizer};
+
+use crate::{
+    Collision, Control, JOURNAL_NAME, Local, Source, Template,
+    file::{self, Browse, Browsed, Known, Layout, Skip},
+    ledger::{Ledger, Record},
+    post::PostID,
+};
+
+/// A file to be moved. Paths are relative to the output folder.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Move {
+    pub from: PathBuf,
+    /// The file, at its new path.
+    pub record: Record,
+}
+
+impl Move {
+    pub fn to(&self) -> &Path {
+        &self.record.path
+    }
+}
+
+/// What moving the files of the output folder to a new format would do.
+#[derive(Debug, Default)]
+pub struct Reorganization {
+    pub out: PathBuf,
+    pub moves: Vec<Move>,
+    /// Moves refused because their destinations are taken.
+    pub conflicts: Vec<Move>,
+    /// Files that are already where they should be.
+    pub unchanged: usize,
+    /// Files whose posts can no longer be found.
+    pub unmatched: Vec<Record>,
+    /// Errors met while fetching the posts.
+    pub errors: Vec<crate::Error>,
+}
+
+/// Which content a file holds.
+type Key = (PostID, Box<str>);
+
+/// Plan where the files in `out` go with the new format.
+///
+/// Files are recognised from the ledger and the metadata saved next to their posts.
+/// Their posts are browsed again to render the new format.
+pub(crate) async fn plan<S: Source>(
+    out: PathBuf,
+    format: Template,
+    sanitizer: Sanitizer,
+    collision: Collision,
+    case_insensitive: bool,
+    source: Arc<S>,
+) -> anyhow::Result<Reorganization> {
+    let known = known_files(&out, &*source).await?;
+
+    // fetch the posts of the known files, artist by artist
+    let mut artists = HashMap::<(Ustr, Ustr), HashSet<PostID>>::new();
+    for record in known.values() {
+        let artist = (record.platform, record.user_id);
+        artists.entry(artist).or_default().insert(record.post);
+    }
+    let mut posts = Vec::new();
+    let mut failures = Vec::new();
+    for ((platform, user_id), ids) in artists {
+        let profile = match source.profile(&platform, &user_id).await {
+            Ok(profile) => profile,
+            Err(e) => {
+                failures.push(crate::Error::Profile(e));
+                continue;
+            }
+        };
+        let range = Range {
+            start: ids.iter().copied().min().unwrap_or_default(),
+            end: ids.iter().copied().max().unwrap_or_default(),
+        };
+        let scraped = match source.posts(&profile, range).await {
+            Ok(scraped) => scraped,
+            Err(e) => {
+                failures.push(crate::Error::Scrape(e));
+                continue;
+            }
+        };
+        let profile = Arc::new(profile);
+        let scraped = scraped
+            .into_iter()
+            .filter(|post| ids.contains(&post.id))
+            .map(|post| (Arc::clone(&profile), post));
+        posts.extend(scraped);
+    }
+
+    // plan them with the new format
+    let layout = Layout {
+        out: out.clone(),
+        format,
+        sanitizer,
+        collision,
+        case_insensitive,
+        save_text: false,
+        save_meta: false,
+        dry_run: true,
+        storage: Arc::new(Local::default()),
+    };
+    let (error_tx, errors) = async_channel::unbounded();
+    // the downloaded files are planned anew, as if nothing was known about them
+    let fresh = Known::new(&layout, false, None);
+    let files_rx = file::collect_files(
+        posts,
+        layout,
+        fresh,
+        source,
+        error_tx,
+        None,
+        &Control::default(),
+    );
+    let mut planned = HashMap::new();
+    while let Ok(browse) = files_rx.recv().await {
+        let Browse::Done(Browsed {
+            collected: (files, skipped),
+            ..
+        }) = browse
+        else {
+            continue;
+        };
+        let files = files.into_iter().map(|file| (file, None));
+        let skipped = skipped.into_iter().map(|(file, skip)| (file, Some(skip)));
+        for (file, skip) in files.chain(skipped) {
+            let key = (file.origin.post, file.key().into());
+            planned.insert(key, (file, skip));
+        }
+    }
+
+    failures.extend(iter::from_fn(|| errors.try_recv().ok()));
+    let mut res = Reorganization {
+        out,
+        errors: failures,
+        ..Default::default()
+    };
+    let sources = known
+        .values()
+        .map(|record| record.path.clone())
+        .collect::<HashSet<_>>();
+    let mut targets = HashSet::new();
+    let mut known = known.into_iter().collect::<Vec<_>>();
+    known.sort_by(|(_, a), (_, b)| a.path.cmp(&b.path));
+    for (key, record) in known {
+        let Some((file, skip)) = planned.get(&key) else {
+            res.unmatched.push(record);
+            continue;
+        };
+        let mut to = file.path().strip_prefix(&res.out)?.to_path_buf();
+        if to == record.path {
+            res.unchanged += 1;
+            continue;
+        }
+        let conflict = match skip {
+            Some(Skip::Collision) => true,
+            // a file that stays is in the way
+            Some(Skip::Exists) => !sources.contains(&to),
+            _ => false,
+        };
+        if conflict {
+            match collision {
+                Collision::Skip => {
+                    let from = record.path.clone();
+                    let record = Record { path: to, ..record };
+                    res.conflicts.push(Move { from, record });
+                    continue;
+                }
+                Collision::Overwrite => {}
+                Collision::Number => to = free(&res.out, &to, &targets).await?,
+            }
+        }
+        targets.insert(to.clone());
+        let from = record.path.clone();
+        let record = Record { path: to, ..record };
+        res.moves.push(Move { from, record });
+    }
+    Ok(res)
+}
+
+impl Reorganization {
+    /// Move the files, journaling every move so they can be undone.
+    ///
+    /// Moving nothing keeps the journal of the last reorganization.
+    pub async fn apply(&self) -> anyhow::Result<()> {
+        if self.moves.is_empty() {
+            return Ok(());
+        }
+        let ledger = Ledger::open(&self.out).await?;
+        // only the last reorganization can be undone
+        let mut journal = fs::File::create(self.out.join(JOURNAL_NAME)).await?;
+        relocate(&self.out, &self.moves, async |mv, done| {
+            let mut line = serde_json::to_string(mv)?;
+            line.push('\n');
+            journal.write_all(line.as_bytes()).await?;
+            journal.flush().await?;
+            match done {
+                true => ledger.append(&mv.record).await,
+                false => Ok(()),
+            }
+        })
+        .await
+    }
+
+    /// Undo the last reorganization of `out`. Returns the number of files moved back.
+    ///
+    /// Files whose old paths are taken again are left alone.
+    pub async fn undo(out: &Path) -> anyhow::Result<usize> {
+        let journal = match fs::read_to_string(out.join(JOURNAL_NAME)).await {
+            Ok(journal) => journal,
+            Err(e) if e.kind() == ErrorKind::NotFound => bail!("Nothing to undo"),
+            Err(e) => return Err(e.into()),
+        };
+        let moves = journal
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Move>(line).ok())
+            .map(|Move { from, record }| Move {
+                from: record.path.clone(),
+                record: Record {
+                    path: from,
+                    ..record
+                },
+            })
+            .collect::<Vec<_>>();
+        let sources = moves
+            .iter()
+            .map(|mv| mv.from.clone())
+            .collect::<HashSet<_>>();
+        let mut back = Vec::new();
+        for mv in moves {
+            let taken = !sources.contains(mv.to()) && fs::try_exists(out.join(mv.to())).await?;
+            if !taken && fs::try_exists(out.join(&mv.from)).await? {
+                back.push(mv);
+            }
+        }
+        let ledger = Ledger::open(out).await?;
+        relocate(out, &back, async |mv, done| match done {
+            true => ledger.append(&mv.record).await,
+            false => Ok(()),
+        })
+        .await?;
+        fs::remove_file(out.join(JOURNAL_NAME)).await?;
+        Ok(back.len())
+    }
+}
+
+/// Move the files in `out`, calling `moved` after each of them, with whether
+/// the file is where it should be or only moved aside.
+///
+/// Files in the way of others are moved aside first, so files can swap their places.
+/// If a move fails, the files still aside are put back where they were, if they can.
+async fn relocate(
+    out: &Path,
+    moves: &[Move],
+    mut moved: impl AsyncFnMut(&Move, bool) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let targets = moves.iter().map(Move::to).collect::<HashSet<_>>();
+    let mut aside = HashMap::new();
+    let res = async {
+        for mv in moves {
+            if targets.contains(mv.from.as_path()) {
+                let tmp = aside_of(out, &mv.from).await?;
+                fs::rename(out.join(&mv.from), out.join(&tmp)).await?;
+                aside.insert(&mv.from, tmp.clone());
+                let record = Record {
+                    path: tmp,
+                    ..mv.record.clone()
+                };
+                let from = mv.from.clone();
+                moved(&Move { from, record }, false).await?;
+            }
+        }
+        for mv in moves {
+            let from = out.join(&mv.from);
+            let to = out.join(mv.to());
+            fs::create_dir_all(to.parent().unwrap()).await?;
+            let tmp = aside.get(&mv.from).map(|tmp| out.join(tmp));
+            fs::rename(tmp.as_ref().unwrap_or(&from), &to).await?;
+            aside.remove(&mv.from);
+            moved(mv, true).await?;
+            prune(out, &from).await;
+        }
+        anyhow::Ok(())
+    }
+    .await;
+    if res.is_err() {
+        for (from, tmp) in aside {
+            let from = out.join(from);
+            if !fs::try_exists(&from).await.unwrap_or(true) {
+                let _ = fs::rename(out.join(tmp), from).await;
+            }
+        }
+    }
+    res
+}
+
+/// A free path next to `path` to move it aside to.
+async fn aside_of(out: &Path, path: &Path) -> anyhow::Result<PathBuf> {
+    let mut n = 0;
+    loop {
+        let mut tmp = OsString::from(path.as_os_str());
+        tmp.push(".aside");
+        if n > 0 {
+            tmp.push(n.to_string());
+        }
+        let tmp = PathBuf::from(tmp);
+        if !fs::try_exists(out.join(&tmp)).await? {
+            return Ok(tmp);
+        }
+        n += 1;
+    }
+}
+
+/// Files that are in `out`, from the metadata of posts and the ledger.
+async fn known_files(out: &Path, source: &impl Source) -> anyhow::Result<HashMap<Key, Record>> {
+    let mut known = HashMap::new();
+    // the ledger is more accurate than the metadata and its later records are more recent
+    let records = sidecars(out, source).await?.into_iter();
+    let records = records.chain(Ledger::records(out).await?);
+    for record in records {
+        if fs::try_exists(out.join(&record.path)).await? {
+            known.insert((record.post, record.key().into()), record);
+        }
+    }
+    Ok(known)
+}
+
+/// Records of the files listed in the metadata of posts saved in `out`.
+///
+/// The artist of a post is told by the source, from the URL of the post.
+async fn sidecars(out: &Path, source: &impl Source) -> anyhow::Result<Vec<Record>> {
+    #[derive(Deserialize)]
+    struct Sidecar {
+        id: PostID,
+        url: String,
+        files: Vec<SidecarFile>,
+    }
+
+    #[derive(Deserialize)]
+    struct SidecarFile {
+        #[serde(default)]
+        index: Option<usize>,
+        hash: Option<Box<str>>,
+        url: Box<str>,
+        path: PathBuf,
+    }
+
+    let mut records = Vec::new();
+    if !fs::try_exists(out).await? {
+        return Ok(records);
+    }
+    let mut dirs = vec![out.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if entry.file_type().await?.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let Ok(sidecar) = fs::read(&path).await else {
+                continue;
+            };
+            let Ok(Sidecar { id, url, files }) = serde_json::from_slice(&sidecar) else {
+                continue;
+            };
+            let Ok((platform, user_id)) = source.parse_url(&url) else {
+                continue;
+            };
+            for SidecarFile {
+                index,
+                hash,
+                url,
+                path,
+            } in files
+            {
+                let Ok(meta) = fs::metadata(out.join(&path)).await else {
+                    continue;
+                };
+                records.push(Record {
+                    platform: platform.into(),
+                    user_id: user_id.into(),
+                    post: id,
+                    index,
+                    hash,
+                    url,
+                    size: meta.len(),
+                    path,
+                });
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// The first numbered variant of `to` that is neither on disk nor planned.
+async fn free(out: &Path, to: &Path, targets: &HashSet<PathBuf>) -> anyhow::Result<PathBuf> {
+    let stem = to.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = to.extension().map(|ext| ext.to_string_lossy());
+    let mut n = 1;
+    loop {
+        let mut filename = format!("{stem} ({n})");
+        if let Some(ext) = &ext {
+            filename.push('.');
+            filename.push_str(ext);
+        }
+        let numbered = to.with_file_name(filename);
+        if !targets.contains(&numbered) && !fs::try_exists(out.join(&numbered)).await? {
+            return Ok(numbered);
+        }
+        n += 1;
+    }
+}
+
+/// Remove the folders left empty after moving `path` away, up to `out`.
+async fn prune(out: &Path, path: &Path) {
+    for dir in path.ancestors().skip(1) {
+        if dir == out || !dir.starts_with(out) || fs::remove_dir(dir).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn mv(from: &str, to: &str) -> Move {
+        let record = Record {
+            platform: "fanbox".into(),
+            user_id: "1".into(),
+            post: 1,
+            index: Some(0),
+            hash: None,
+            url: format!("https://example.com/{from}").into(),
+            size: 1,
+            path: to.into(),
+        };
+        Move {
+            from: from.into(),
+            record,
+        }
+    }
+
+    fn put(out: &Path, files: &[&str]) {
+        for file in files {
+            let path = out.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, file).unwrap();
+        }
+    }
+
+    /// What the file at `path` was called before any move.
+    fn was(out: &Path, path: &str) -> String {
+        std::fs::read_to_string(out.join(path)).unwrap()
+    }
+
+    fn asides(out: &Path) -> usize {
+        std::fs::read_dir(out)
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().contains(".aside")
+            })
+            .count()
+    }
+
+    fn reorganization(out: &Path, moves: Vec<Move>) -> Reorganization {
+        Reorganization {
+            out: out.into(),
+            moves,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn applies_and_undoes_moves() {
+        let out = TempDir::new().unwrap();
+        let out = out.path();
+        put(out, &["a.png", "b/c.png"]);
+        let moves = vec![mv("a.png", "x/a.png"), mv("b/c.png", "c.png")];
+        reorganization(out, moves).apply().await.unwrap();
+
+        assert_eq!(was(out, "x/a.png"), "a.png");
+        assert_eq!(was(out, "c.png"), "b/c.png");
+        // emptied folders go away
+        assert!(!out.join("b").exists());
+        let records = Ledger::records(out).await.unwrap();
+        let paths = records.iter().map(|r| r.path.as_path()).collect::<Vec<_>>();
+        assert_eq!(paths, [Path::new("x/a.png"), Path::new("c.png")]);
+
+        assert_eq!(Reorganization::undo(out).await.unwrap(), 2);
+        assert_eq!(was(out, "a.png"), "a.png");
+        assert_eq!(was(out, "b/c.png"), "b/c.png");
+        assert!(!out.join(JOURNAL_NAME).exists());
+        assert!(Reorganization::undo(out).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn swaps_files() {
+        let out = TempDir::new().unwrap();
+        let out = out.path();
+        put(out, &["a.png", "b.png"]);
+        let moves = vec![mv("a.png", "b.png"), mv("b.png", "a.png")];
+        reorganization(out, moves).apply().await.unwrap();
+        assert_eq!(was(out, "a.png"), "b.png");
+        assert_eq!(was(out, "b.png"), "a.png");
+        assert_eq!(asides(out), 0);
+
+        assert_eq!(Reorganization::undo(out).await.unwrap(), 2);
+        assert_eq!(was(out, "a.png"), "a.png");
+        assert_eq!(was(out, "b.png"), "b.png");
+        assert_eq!(asides(out), 0);
+    }
+
+    #[tokio::test]
+    async fn puts_files_back_when_a_move_fails() {
+        let out = TempDir::new().unwrap();
+        let out = out.path();
+        // no folder can be made where a file is
+        put(out, &["a.png", "b.png", "c.png", "blocker"]);
+        let moves = vec![
+            mv("c.png", "blocker/c.png"),
+            mv("a.png", "b.png"),
+            mv("b.png", "a.png"),
+        ];
+        assert!(reorganization(out, moves).apply().await.is_err());
+        assert_eq!(was(out, "a.png"), "a.png");
+        assert_eq!(was(out, "b.png"), "b.png");
+        assert_eq!(was(out, "c.png"), "c.png");
+        assert_eq!(asides(out), 0);
+    }
+
+    #[tokio::test]
+    async fn undoes_files_left_aside() {
+        let out = TempDir::new().unwrap();
+        let out = out.path();
+        // a swap cut short: `a.png` took the place of `b.png`, which is still aside
+        put(out, &["b.png.aside"]);
+        std::fs::write(out.join("b.png"), "a.png").unwrap();
+        let journal = [mv("b.png", "b.png.aside"), mv("a.png", "b.png")]
+            .iter()
+            .map(|mv| serde_json::to_string(mv).unwrap() + "\n")
+            .collect::<String>();
+        std::fs::write(out.join(JOURNAL_NAME), journal).unwrap();
+
+        assert_eq!(Reorganization::undo(out).await.unwrap(), 2);
+        assert_eq!(was(out, "a.png"), "a.png");
+        assert_eq!(was(out, "b.png"), "b.png.aside");
+        assert_eq!(asides(out), 0);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_journal_when_nothing_moves() {
+        let out = TempDir::new().unwrap();
+        let out = out.path();
+        put(out, &["a.png"]);
+        reorganization(out, vec![mv("a.png", "b.png")])
+            .apply()
+            .await
+            .unwrap();
+        reorganization(out, Vec::new()).apply().await.unwrap();
+        assert_eq!(Reorganization::undo(out).await.unwrap(), 1);
+        assert_eq!(was(out, "a.png"), "a.png");
+    }
+
+    #[tokio::test]
+    async fn numbers_taken_destinations() {
+        let out = TempDir::new().unwrap();
+        let out = out.path();
+        put(out, &["a.png", "a (1).png"]);
+        let planned = HashSet::from([PathBuf::from("a (2).png")]);
+        let free = free(out, Path::new("a.png"), &planned).await.unwrap();
+        assert_eq!(free, Path::new("a (3).png"));
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use async_channel::{Receiver, Sender};
+use serde::Serialize;
+use serde_with::{DurationSecondsWithFrac, serde_as};
+use tokio::{
+    fs,
+    time::{self, Instant, MissedTickBehavior},
+};
+use yaks_common::SenderExt;
+
+use crate::{Control, Event, Failure, STATS_INTERVAL};
+
+/// Counts of a run so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Stats {
+    /// Posts scraped.
+    pub posts: usize,
+    /// Posts browsed.
+    pub browsed: usize,
+    /// Files queued for download.
+    pub files: usize,
+    /// Files not downloaded, for whatever reason.
+    pub skipped: usize,
+    pub downloaded: usize,
+    pub failed: usize,
+    /// Bytes received.
+    pub bytes: u64,
+    /// Bytes announced by the server for the files started so far.
+    pub expected: u64,
+}
+
+/// Summary of a run, sent right before it is cleared,
+/// or last when it ends early.
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+pub struct RunReport {
+    pub stats: Stats,
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub elapsed: Duration,
+    /// Errors of any kind, including the failed files.
+    pub failures: Vec<Failure>,
+    pub cancelled: bool,
+}
+
+/// Count the events on their way to the UI.
+///
+/// The stats are sent every `STATS_INTERVAL` when they have changed,
+/// and the report right before `Event::Clear`, or once the events end
+/// when the run stops early. The report is saved as JSON at `save`, if given,
+/// before it is sent, so it is kept even if the UI is gone.
+pub(crate) fn listen_events(
+    events: Receiver<crate::Result<Event>>,
+    ui: Sender<crate::Result<Event>>,
+    save: Option<PathBuf>,
+    control: &Control,
+) {
+    let ctrl = control.clone();
+    control.spawn(async move {
+        let start = Instant::now();
+        let mut stats = Stats::default();
+        let mut sent = stats;
+        let mut failures = Vec::new();
+        let mut reported = None;
+        let mut ticks = time::interval(STATS_INTERVAL);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            let event = tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => event,
+                    Err(_) => break,
+                },
+                _ = ticks.tick() => {
+                    if stats != sent {
+                        if ui.deliver(Ok(Event::Stats(stats))).await.is_err() {
+                            break;
+                        }
+                        sent = stats;
+                    }
+                    continue;
+                }
+            };
+            match &event {
+                Ok(Event::Posts(posts)) => stats.posts += posts,
+                Ok(Event::Files(files)) => {
+                    stats.browsed += 1;
+                    stats.files += files.len();
+                }
+                Ok(Event::Skipped(skipped)) => stats.skipped += skipped.len(),
+                Ok(Event::Init(_, size)) => stats.expected += size,
+                Ok(Event::Chunk(_, size)) => stats.bytes += size,
+                Ok(Event::Fin(_)) => stats.downloaded += 1,
+                Ok(Event::Clear) => {
+                    let report = RunReport {
+                        stats,
+                        elapsed: start.elapsed(),
+                        failures: std::mem::take(&mut failures),
+                        cancelled: ctrl.is_cancelled(),
+                    };
+                    save_report(save.as_deref(), &report).await;
+                    let delivered = ui.deliver(Ok(Event::Report(report.clone()))).await;
+                    reported = Some(report);
+                    if delivered.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    if let crate::Error::Download(..) = e {
+                        stats.failed += 1;
+                    }
+                    failures.push(Failure::from(e));
+                }
+                Ok(_) => {}
+            }
+            // the run notices the UI is gone once these events are dropped
+            if ui.deliver(event).await.is_err() {
+                break;
+            }
+        }
+        // runs stopped by an error, cancelled or left by the UI never get to `Clear`
+        if reported.is_none() {
+            let report = RunReport {
+                stats,
+                elapsed: start.elapsed(),
+                failures,
+                cancelled: ctrl.is_cancelled(),
+            };
+            save_report(save.as_deref(), &report).await;
+            let _ = ui.deliver(Ok(Event::Report(report))).await;
+        }
+        Ok(())
+    });
+}
+
+/// Save the report as JSON at `save`, if given.
+async fn save_report(save: Option<&Path>, report: &RunReport) {
+    if let Some(save) = save
+        && let Ok(json) = serde_json::to_vec_pretty(report)
+    {
+        // the UI gets the report anyway, and the folder may not exist yet
+        if let Some(parent) = save.parent() {
+            let _ = fs::create_dir_all(parent).await;
+        }
+        let _ = fs::write(save, json).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::Error;
+
+    #[tokio::test]
+    async fn saves_the_report_once_the_ui_is_gone() {
+        let out = tempfile::TempDir::new().unwrap();
+        let save = out.path().join(crate::REPORT_NAME);
+        let control = Control::default();
+        let (events, counted) = async_channel::unbounded();
+        let (ui, rx) = async_channel::unbounded();
+        listen_events(counted, ui, Some(save.clone()), &control);
+
+        let error = Error::Profile(anyhow!("404 Not Found"));
+        events.send(Err(error)).await.unwrap();
+        assert!(rx.recv().await.unwrap().is_err());
+        drop(rx);
+        drop(events);
+        control.shutdown().await;
+
+        let report = std::fs::read_to_string(save).unwrap();
+        assert!(report.contains("404 Not Found"), "{report}");
+    }
+}
@@ -0,0 +1,102 @@
+use std::{fmt, future::Future};
+
+use async_channel::Receiver;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use yaks_common::Range;
+
+use crate::{
+    file::File,
+    post::{Post, PostID, Profile},
+    rate::Pace,
+};
+
+/// Where the artists, their posts and the files of the posts come from.
+///
+/// The engine only asks its source, never the network, so anything able to
+/// answer these questions can be downloaded from: another site, a replay of
+/// a previous run, or posts kept in memory.
+pub trait Source: Send + Sync + 'static {
+    /// What `{site}` stands for.
+    fn name(&self) -> &str;
+
+    /// Split the URL of an artist into their platform and user ID.
+    fn parse_url<'a>(&self, url: &'a str) -> anyhow::Result<(&'a str, &'a str)>;
+
+    /// Where the post can be seen, as saved in its metadata.
+    fn post_url(&self, profile: &Profile, post: PostID) -> String;
+
+    /// Get the profile of the artist, with their username.
+    fn profile(
+        &self,
+        platform: &str,
+        user_id: &str,
+    ) -> impl Future<Output = anyhow::Result<Profile>> + Send;
+
+    /// The profiles of the accounts linked to the artist on other platforms.
+    ///
+    /// They should share the nickname of the given one so that
+    /// all their files can go into the same folder. An account that can not
+    /// be loaded does not keep the others from being downloaded.
+    fn linked(
+        &self,
+        profile: &Profile,
+    ) -> impl Future<Output = anyhow::Result<Vec<anyhow::Result<Profile>>>> + Send;
+
+    /// The posts of the artist within the range, newest first.
+    fn posts(
+        &self,
+        profile: &Profile,
+        range: Range,
+    ) -> impl Future<Output = anyhow::Result<Vec<Post>>> + Send;
+
+    /// What the post holds.
+    fn content(
+        &self,
+        profile: &Profile,
+        post: &Post,
+    ) -> impl Future<Output = anyhow::Result<Content>> + Send;
+
+    /// The body of a file, from the `url` of its attachment.
+    fn body(&self, file: &File) -> impl Future<Output = anyhow::Result<Body>> + Send;
+
+    /// Where the pace of the requests is reported, if they are paced at all.
+    fn rates(&self) -> Option<Receiver<Pace>> {
+        None
+    }
+}
+
+/// The text, tags and files of a post.
+#[derive(Debug, Clone, Default)]
+pub struct Content {
+    /// In HTML. Saved as markdown.
+    pub text: String,
+    pub tags: Vec<String>,
+    /// In the order of the post, which gives their `{index}`.
+    pub attachments: Vec<Attachment>,
+}
+
+/// A file of a post, as told by the source.
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub filename: String,
+    /// Where the file is downloaded from.
+    pub url: String,
+    /// The SHA-256 of the content, if known.
+    pub hash: Option<String>,
+}
+
+/// The content of a file, as it arrives.
+pub struct Body {
+    /// The size announced before the content. A body of another size is refused.
+    pub size: u64,
+    pub chunks: BoxStream<'static, anyhow::Result<Bytes>>,
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Body")
+            .field("size", &self.size)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,381 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::File as StdFile,
+    io::{self, BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use anyhow::anyhow;
+use futures::future::{self, BoxFuture};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex as AsyncMutex, task};
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+use crate::SPOOL_NAME;
+
+/// Where downloaded files, and the texts and metadata of posts, are written.
+///
+/// A file is created at a temporary path and appended to chunk by chunk, then
+/// either committed to its final path or discarded. Many files are written at
+/// once, but each of them by a single writer.
+pub trait Storage: fmt::Debug + Send + Sync {
+    /// Start writing a file at `path`, replacing whatever was there.
+    fn create<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    fn append<'a>(&'a self, path: &'a Path, chunk: &'a [u8]) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    /// Finish the file written at `path` and move it to `dest`.
+    fn commit<'a>(&'a self, path: &'a Path, dest: &'a Path) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    /// Give up on the file written at `path`.
+    fn discard<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    /// Whether a file has been committed at `path`.
+    fn exists<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, anyhow::Result<bool>>;
+
+    /// Write a whole file at once.
+    fn write<'a>(
+        &'a self,
+        path: &'a Path,
+        contents: &'a [u8],
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            self.create(path).await?;
+            self.append(path, contents).await?;
+            self.commit(path, path).await
+        })
+    }
+
+    /// Close the storage once every file is written.
+    fn finish(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(future::ready(Ok(())))
+    }
+
+    /// Whether committed files are on the local disk, at the path they are committed to.
+    /// Only those can be extracted.
+    fn on_disk(&self) -> bool {
+        false
+    }
+}
+
+/// Files saved into the local file system.
+#[derive(Debug, Default)]
+pub struct Local {
+    /// Files being written, by path.
+    drafts: Mutex<HashMap<PathBuf, Arc<AsyncMutex<fs::File>>>>,
+}
+
+impl Local {
+    fn draft(&self, path: &Path) -> anyhow::Result<Arc<AsyncMutex<fs::File>>> {
+        let drafts = self.drafts.lock().unwrap();
+        drafts.get(path).cloned().ok_or_else(|| not_written(path))
+    }
+}
+
+impl Storage for Local {
+    fn create<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            fs::create_dir_all(path.parent().unwrap()).await?;
+            let file = fs::File::create(path).await?;
+            let draft = Arc::new(AsyncMutex::new(file));
+            self.drafts.lock().unwrap().insert(path.into(), draft);
+            Ok(())
+        })
+    }
+
+    fn append<'a>(&'a self, path: &'a Path, chunk: &'a [u8]) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let draft = self.draft(path)?;
+            draft.lock().await.write_all(chunk).await?;
+            Ok(())
+        })
+    }
+
+    fn commit<'a>(&'a self, path: &'a Path, dest: &'a Path) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let draft = self.drafts.lock().unwrap().remove(path);
+            let draft = draft.ok_or_else(|| not_written(path))?;
+            // written in the background otherwise, possibly after the rename
+            draft.lock().await.flush().await?;
+            drop(draft);
+            if path != dest {
+                fs::create_dir_all(dest.parent().unwrap()).await?;
+                fs::rename(path, dest).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn discard<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            // close the file before removing it
+            self.drafts.lock().unwrap().remove(path);
+            match fs::remove_file(path).await {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    fn exists<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move { Ok(fs::try_exists(path).await?) })
+    }
+
+    fn on_disk(&self) -> bool {
+        true
+    }
+}
+
+/// What the files of a run are packed into, instead of a folder.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pack {
+    /// A tar archive, streamed to the standard output if the path is `-`.
+    Tar(PathBuf),
+    /// A zip archive.
+    Zip(PathBuf),
+}
+
+/// Files packed into an archive as soon as they are committed.
+///
+/// The size of an entry comes before its content, so files are spooled on disk
+/// until they are committed. Entries are named after their paths within `root`.
+pub struct Archive {
+    root: PathBuf,
+    spool: PathBuf,
+    local: Local,
+    /// Spooled file of each path being written.
+    drafts: Mutex<HashMap<PathBuf, PathBuf>>,
+    spooled: AtomicUsize,
+    packed: Mutex<HashSet<PathBuf>>,
+    /// Taken once the archive is finished.
+    packer: Arc<Mutex<Option<Packer>>>,
+}
+
+enum Packer {
+    Tar(tar::Builder<Box<dyn Write + Send>>),
+    Zip(Box<ZipWriter<BufWriter<StdFile>>>),
+}
+
+impl Archive {
+    /// Start the archive. Files must be committed within `root`.
+    pub fn new(pack: &Pack, root: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let packer = match pack {
+            Pack::Tar(path) if path.as_os_str() == "-" => {
+                let stdout: Box<dyn Write + Send> = Box::new(BufWriter::new(io::stdout()));
+                Packer::Tar(tar::Builder::new(stdout))
+            }
+            Pack::Tar(path) => {
+                let file: Box<dyn Write + Send> = Box::new(BufWriter::new(StdFile::create(path)?));
+                Packer::Tar(tar::Builder::new(file))
+            }
+            Pack::Zip(path) => {
+                let file = BufWriter::new(StdFile::create(path)?);
+                Packer::Zip(Box::new(ZipWriter::new(file)))
+            }
+        };
+        let root = root.into();
+        Ok(Self {
+            spool: root.join(SPOOL_NAME),
+            root,
+            local: Local::default(),
+            drafts: Mutex::new(HashMap::new()),
+            spooled: AtomicUsize::new(0),
+            packed: Mutex::new(HashSet::new()),
+            packer: Arc::new(Mutex::new(Some(packer))),
+        })
+    }
+
+    fn draft(&self, path: &Path) -> anyhow::Result<PathBuf> {
+        let drafts = self.drafts.lock().unwrap();
+        drafts.get(path).cloned().ok_or_else(|| not_written(path))
+    }
+}
+
+impl fmt::Debug for Archive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Archive")
+            .field("root", &self.root)
+            .field("spool", &self.spool)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Storage for Archive {
+    fn create<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let n = self.spooled.fetch_add(1, Ordering::Relaxed);
+            let spooled = self.spool.join(n.to_string());
+            self.local.create(&spooled).await?;
+            self.drafts.lock().unwrap().insert(path.into(), spooled);
+            Ok(())
+        })
+    }
+
+    fn append<'a>(&'a self, path: &'a Path, chunk: &'a [u8]) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move { self.local.append(&self.draft(path)?, chunk).await })
+    }
+
+    fn commit<'a>(&'a self, path: &'a Path, dest: &'a Path) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let spooled = self.drafts.lock().unwrap().remove(path);
+            let spooled = spooled.ok_or_else(|| not_written(path))?;
+            self.local.commit(&spooled, &spooled).await?;
+            let name = dest
+                .strip_prefix(&self.root)?
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            let packer = Arc::clone(&self.packer);
+            let spool = spooled.clone();
+            // entries are written one at a time, by blocking writers
+            task::spawn_blocking(move || {
+                let mut file = StdFile::open(spool)?;
+                let mut packer = packer.lock().unwrap();
+                let packer = packer
+                    .as_mut()
+                    .ok_or(anyhow!("The archive is already finished"))?;
+                packer.add(&name, &mut file)
+            })
+            .await??;
+            fs::remove_file(&spooled).await?;
+            self.packed.lock().unwrap().insert(dest.into());
+            Ok(())
+        })
+    }
+
+    fn discard<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let spooled = self.drafts.lock().unwrap().remove(path);
+            match spooled {
+                Some(spooled) => self.local.discard(&spooled).await,
+                None => Ok(()),
+            }
+        })
+    }
+
+    fn exists<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(future::ready(Ok(self
+            .packed
+            .lock()
+            .unwrap()
+            .contains(path))))
+    }
+
+    fn finish(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move {
+            let packer = self.packer.lock().unwrap().take();
+            if let Some(packer) = packer {
+                task::spawn_blocking(move || packer.finish()).await??;
+            }
+            match fs::remove_dir_all(&self.spool).await {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            }
+        })
+    }
+}
+
+impl Packer {
+    fn add(&mut self, name: &str, file: &mut StdFile) -> anyhow::Result<()> {
+        match self {
+            Self::Tar(builder) => builder.append_file(name, file)?,
+            Self::Zip(writer) => {
+                let size = file.metadata()?.len();
+                // what is downloaded is mostly compressed already
+                let options = SimpleFileOptions::default()
+                    .compression_method(CompressionMethod::Stored)
+                    .large_file(size >= u32::MAX as u64);
+                writer.start_file(name, options)?;
+                io::copy(file, &mut **writer)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        match self {
+            Self::Tar(builder) => builder.into_inner()?.flush()?,
+            Self::Zip(writer) => writer.finish()?.flush()?,
+        }
+        Ok(())
+    }
+}
+
+/// Files kept in memory, mostly for tests.
+#[derive(Debug, Default)]
+pub struct Memory {
+    drafts: Mutex<HashMap<PathBuf, Vec<u8>>>,
+    files: Mutex<HashMap<PathBuf, Vec<u8>>>,
+}
+
+impl Memory {
+    /// The content of the file committed at `path`.
+    pub fn get(&self, path: &Path) -> Option<Vec<u8>> {
+        self.files.lock().unwrap().get(path).cloned()
+    }
+
+    /// The paths of the committed files, sorted.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        let mut paths = self
+            .files
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+}
+
+impl Storage for Memory {
+    fn create<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, anyhow::Result<()>> {
+        self.drafts.lock().unwrap().insert(path.into(), Vec::new());
+        Box::pin(future::ready(Ok(())))
+    }
+
+    fn append<'a>(&'a self, path: &'a Path, chunk: &'a [u8]) -> BoxFuture<'a, anyhow::Result<()>> {
+        let mut drafts = self.drafts.lock().unwrap();
+        let res = match drafts.get_mut(path) {
+            Some(draft) => {
+                draft.extend_from_slice(chunk);
+                Ok(())
+            }
+            None => Err(not_written(path)),
+        };
+        Box::pin(future::ready(res))
+    }
+
+    fn commit<'a>(&'a self, path: &'a Path, dest: &'a Path) -> BoxFuture<'a, anyhow::Result<()>> {
+        let draft = self.drafts.lock().unwrap().remove(path);
+        let res = match draft {
+            Some(draft) => {
+                self.files.lock().unwrap().insert(dest.into(), draft);
+                Ok(())
+            }
+            None => Err(not_written(path)),
+        };
+        Box::pin(future::ready(res))
+    }
+
+    fn discard<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, anyhow::Result<()>> {
+        self.drafts.lock().unwrap().remove(path);
+        Box::pin(future::ready(Ok(())))
+    }
+
+    fn exists<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, anyhow::Result<bool>> {
+        let exists = self.files.lock().unwrap().contains_key(path);
+        Box::pin(future::ready(Ok(exists)))
+    }
+}
+
+fn not_written(path: &Path) -> anyhow::Error {
+    anyhow!("`{}` is not being written", path.display())
+}
@@ -0,0 +1,616 @@
+use std::{
+    fmt::{self, Display},
+    iter::Peekable,
+    str::{Chars, FromStr},
+};
+
+use anyhow::{Context, anyhow, bail};
+use chrono::{
+    NaiveDateTime,
+    format::{Item, StrftimeItems},
+};
+use yaks_common::Sanitizer;
+
+/// A parsed output format, like `{nickname}/{published:%Y-%m}/{title|truncate(60)}_{index:03}`.
+///
+/// - Placeholders are substituted in one pass so a value that looks like
+///   a placeholder (e.g. a post titled `{index}`) is never substituted again.
+/// - A placeholder takes an optional specifier after `:` (zero-padding for
+///   numbers, a strftime format for dates) and any number of filters after `|`.
+/// - Segments wrapped in `<>` are dropped if any of their placeholders is empty.
+/// - `{{`, `}}`, `<<` and `>>` stand for the literal characters.
+#[derive(Debug, Clone)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Field(Field),
+    Optional(Vec<Segment>),
+}
+
+#[derive(Debug, Clone)]
+struct Field {
+    key: Key,
+    spec: Spec,
+    filters: Vec<Filter>,
+}
+
+/// Names of the placeholders.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    // artist-level
+    Nickname,
+    Username,
+    UserId,
+    Platform,
+    Site,
+    // post-level
+    PostId,
+    Title,
+    Published,
+    Added,
+    Tags,
+    FileCount,
+    // file-level
+    Filename,
+    Stem,
+    Ext,
+    Hash,
+    Index,
+}
+
+/// Values of the placeholders.
+#[derive(Debug, Clone, Copy)]
+pub enum Value<'a> {
+    Str(&'a str),
+    Int(u64),
+    Date(NaiveDateTime),
+}
+
+enum Kind {
+    Str,
+    Int,
+    Date,
+}
+
+#[derive(Debug, Clone)]
+enum Spec {
+    None,
+    Width {
+        width: usize,
+        zero: bool,
+    },
+    Date {
+        format: Box<str>,
+        items: Vec<Item<'static>>,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum Filter {
+    Truncate(usize),
+    Lower,
+    Upper,
+}
+
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+
+impl Key {
+    const ALL: [Key; 16] = [
+        Key::Nickname,
+        Key::Username,
+        Key::UserId,
+        Key::Platform,
+        Key::Site,
+        Key::PostId,
+        Key::Title,
+        Key::Published,
+        Key::Added,
+        Key::Tags,
+        Key::FileCount,
+        Key::Filename,
+        Key::Stem,
+        Key::Ext,
+        Key::Hash,
+        Key::Index,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Key::Nickname => "nickname",
+            Key::Username => "username",
+            Key::UserId => "user_id",
+            Key::Platform => "platform",
+            Key::Site => "site",
+            Key::PostId => "post_id",
+            Key::Title => "title",
+            Key::Published => "published",
+            Key::Added => "added",
+            Key::Tags => "tags",
+            Key::FileCount => "file_count",
+            Key::Filename => "filename",
+            Key::Stem => "stem",
+            Key::Ext => "ext",
+            Key::Hash => "hash",
+            Key::Index => "index",
+        }
+    }
+
+    fn kind(self) -> Kind {
+        match self {
+            Key::PostId | Key::FileCount | Key::Index => Kind::Int,
+            Key::Published | Key::Added => Kind::Date,
+            _ => Kind::Str,
+        }
+    }
+}
+
+impl FromStr for Key {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Key::ALL
+            .into_iter()
+            .find(|key| key.name() == s)
+            .ok_or(anyhow!("Unknown placeholder `{{{s}}}`"))
+    }
+}
+
+impl Template {
+    /// Substitute the placeholders. Values are made path-safe after the filters.
+    ///
+    /// The result is not yet a valid path. See `Sanitizer::path`.
+    pub fn render<'a>(
+        &self,
+        sanitizer: &Sanitizer,
+        vars: impl Fn(Key) -> Option<Value<'a>>,
+    ) -> String {
+        let mut out = String::new();
+        render_segments(&self.segments, sanitizer, &vars, &mut out);
+        out
+    }
+
+    /// Whether the placeholder appears anywhere, optional segments included.
+    pub fn uses(&self, key: Key) -> bool {
+        fn uses(segments: &[Segment], key: Key) -> bool {
+            segments.iter().any(|segment| match segment {
+                Segment::Literal(_) => false,
+                Segment::Field(field) => field.key == key,
+                Segment::Optional(segments) => uses(segments, key),
+            })
+        }
+        uses(&self.segments, key)
+    }
+
+    /// Whether the last segment is one of the given placeholders.
+    pub fn ends_with(&self, keys: &[Key]) -> bool {
+        matches!(self.segments.last(), Some(Segment::Field(field)) if keys.contains(&field.key))
+    }
+
+    /// Derive the template of post-level content (like the text) with the given extension.
+    ///
+    /// The template is cut after its last `{post_id}`/`{title}`. If posts are arranged
+    /// into separate folders, the content goes into the folder as `post.{ext}`.
+    pub fn for_post(&self, ext: &str) -> Template {
+        let last = self.segments.iter().rposition(Segment::identifies_post);
+        let mut segments;
+        if let Some(last) = last {
+            let append = match self.segments.get(last + 1) {
+                // user arrange posts into separate folders
+                Some(Segment::Literal(literal)) if literal.starts_with('/') => "/post.",
+                // user arrange posts under one big folder
+                _ => ".",
+            };
+            segments = self.segments[..=last].to_vec();
+            segments.push(Segment::Literal(format!("{append}{ext}")));
+        } else {
+            // post-level meta is missing.
+            segments = self.segments.clone();
+            segments.push(Segment::Field(Field::from(Key::PostId)));
+            segments.push(Segment::Literal("_".into()));
+            segments.push(Segment::Field(Field::from(Key::Title)));
+            segments.push(Segment::Literal(format!(".{ext}")));
+        }
+        Template { segments }
+    }
+}
+
+impl FromStr for Template {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.replace("\\", "/");
+        let mut chars = s.trim_start_matches('/').chars().peekable();
+        let segments =
+            parse_segments(&mut chars, false).with_context(|| format!("Invalid format `{s}`"))?;
+        Ok(Template { segments })
+    }
+}
+
+impl Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.segments.iter().try_for_each(|seg| write!(f, "{seg}"))
+    }
+}
+
+impl Segment {
+    fn identifies_post(&self) -> bool {
+        match self {
+            Segment::Field(field) => matches!(field.key, Key::PostId | Key::Title),
+            Segment::Optional(segments) => segments.iter().any(Segment::identifies_post),
+            Segment::Literal(_) => false,
+        }
+    }
+}
+
+impl Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Segment::Literal(literal) => {
+                for c in literal.chars() {
+                    match c {
+                        '{' | '}' | '<' | '>' => write!(f, "{c}{c}")?,
+                        c => write!(f, "{c}")?,
+                    }
+                }
+                Ok(())
+            }
+            Segment::Field(field) => write!(f, "{field}"),
+            Segment::Optional(segments) => {
+                write!(f, "<")?;
+                segments.iter().try_for_each(|seg| write!(f, "{seg}"))?;
+                write!(f, ">")
+            }
+        }
+    }
+}
+
+/// Returns `true` if all the placeholders are non-empty.
+fn render_segments<'a>(
+    segments: &[Segment],
+    sanitizer: &Sanitizer,
+    vars: &impl Fn(Key) -> Option<Value<'a>>,
+    out: &mut String,
+) -> bool {
+    let mut complete = true;
+    for seg in segments {
+        match seg {
+            Segment::Literal(literal) => out.push_str(literal),
+            Segment::Field(field) => {
+                match vars(field.key).map(|val| field.render(val, sanitizer)) {
+                    Some(rendered) if !rendered.is_empty() => out.push_str(&rendered),
+                    _ => complete = false,
+                }
+            }
+            Segment::Optional(segments) => {
+                let mut buf = String::new();
+                if render_segments(segments, sanitizer, vars, &mut buf) {
+                    out.push_str(&buf);
+                }
+            }
+        }
+    }
+    complete
+}
+
+fn parse_segments(chars: &mut Peekable<Chars>, nested: bool) -> anyhow::Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let flush = |literal: &mut String, segments: &mut Vec<Segment>| {
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(std::mem::take(literal)));
+        }
+    };
+    while let Some(c) = chars.next() {
+        match c {
+            '{' | '}' | '<' | '>' if chars.next_if_eq(&c).is_some() => literal.push(c),
+            '{' => {
+                flush(&mut literal, &mut segments);
+                let mut field = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => field.push(c),
+                        None => bail!("Unclosed `{{`"),
+                    }
+                }
+                segments.push(Segment::Field(field.parse()?));
+            }
+            '<' if nested => bail!("Nested `<` is not supported"),
+            '<' => {
+                flush(&mut literal, &mut segments);
+                segments.push(Segment::Optional(parse_segments(chars, true)?));
+            }
+            '>' if nested => {
+                flush(&mut literal, &mut segments);
+                return Ok(segments);
+            }
+            '}' | '>' => bail!("Unmatched `{c}`"),
+            c => literal.push(c),
+        }
+    }
+    if nested {
+        bail!("Unclosed `<`")
+    }
+    flush(&mut literal, &mut segments);
+    Ok(segments)
+}
+
+impl From<Key> for Field {
+    fn from(key: Key) -> Self {
+        Field {
+            key,
+            spec: Spec::None,
+            filters: Vec::new(),
+        }
+    }
+}
+
+impl Field {
+    fn render(&self, val: Value, sanitizer: &Sanitizer) -> String {
+        let mut rendered = match (val, &self.spec) {
+            (Value::Int(n), Spec::Width { width, zero: true }) => format!("{n:0width$}"),
+            (Value::Int(n), Spec::Width { width, zero: false }) => format!("{n:width$}"),
+            (Value::Int(n), _) => n.to_string(),
+            (Value::Date(date), Spec::Date { items, .. }) => {
+                date.format_with_items(items.iter()).to_string()
+            }
+            (Value::Date(date), _) => date.format(DEFAULT_DATE_FORMAT).to_string(),
+            (Value::Str(s), _) => s.to_string(),
+        };
+        for filter in &self.filters {
+            rendered = match filter {
+                Filter::Truncate(len) => rendered.chars().take(*len).collect(),
+                Filter::Lower => rendered.to_lowercase(),
+                Filter::Upper => rendered.to_uppercase(),
+            };
+        }
+        sanitizer.value(&rendered)
+    }
+}
+
+impl FromStr for Field {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('|');
+        let head = parts.next().unwrap_or_default();
+        let (name, spec) = match head.split_once(':') {
+            Some((name, spec)) => (name, Some(spec)),
+            None => (head, None),
+        };
+        let key: Key = name.trim().parse()?;
+        let spec = match (spec, key.kind()) {
+            (None, _) => Spec::None,
+            (Some(spec), Kind::Int) => {
+                // only digits, where `parse` would also take a sign
+                let width = Some(spec)
+                    .filter(|spec| spec.bytes().all(|b| b.is_ascii_digit()))
+                    .and_then(|spec| spec.parse().ok())
+                    .ok_or(anyhow!("Invalid width `{spec}` for `{{{name}}}`"))?;
+                let zero = spec.starts_with('0');
+                Spec::Width { width, zero }
+            }
+            (Some(spec), Kind::Date) => {
+                let items = StrftimeItems::new(spec)
+                    .parse_to_owned()
+                    .map_err(|_| anyhow!("Invalid date format `{spec}` for `{{{name}}}`"))?;
+                Spec::Date {
+                    format: spec.into(),
+                    items,
+                }
+            }
+            (Some(_), Kind::Str) => bail!("`{{{name}}}` does not accept a specifier"),
+        };
+        let filters = parts.map(str::parse).collect::<anyhow::Result<_>>()?;
+        Ok(Field { key, spec, filters })
+    }
+}
+
+impl Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{{{}", self.key.name())?;
+        match &self.spec {
+            Spec::None => {}
+            Spec::Width { width, zero } => {
+                let zero = if *zero { "0" } else { "" };
+                write!(f, ":{zero}{width}")?
+            }
+            Spec::Date { format, .. } => write!(f, ":{format}")?,
+        }
+        for filter in &self.filters {
+            write!(f, "|{filter}")?;
+        }
+        write!(f, "}}")
+    }
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (name, arg) = match s.split_once('(') {
+            Some((name, arg)) => {
+                let arg = arg
+                    .strip_suffix(')')
+                    .ok_or(anyhow!("Unclosed `(` in filter `{s}`"))?;
+                (name, Some(arg))
+            }
+            None => (s, None),
+        };
+        let filter = match (name, arg) {
+            ("truncate", Some(len)) => {
+                let len = len
+                    .trim()
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid length `{len}` for filter `truncate`"))?;
+                Filter::Truncate(len)
+            }
+            ("lower", None) => Filter::Lower,
+            ("upper", None) => Filter::Upper,
+            _ => bail!("Unknown filter `{s}`"),
+        };
+        Ok(filter)
+    }
+}
+
+impl Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filter::Truncate(len) => write!(f, "truncate({len})"),
+            Filter::Lower => write!(f, "lower"),
+            Filter::Upper => write!(f, "upper"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn vars(key: Key) -> Option<Value<'static>> {
+        let published = NaiveDate::from_ymd_opt(2024, 5, 1)?.and_hms_opt(12, 0, 0)?;
+        match key {
+            Key::Nickname => Some(Value::Str("Alice")),
+            Key::Title => Some(Value::Str("Hello World")),
+            Key::Tags => Some(Value::Str("")),
+            Key::Published => Some(Value::Date(published)),
+            Key::PostId => Some(Value::Int(100)),
+            Key::Index => Some(Value::Int(7)),
+            _ => None,
+        }
+    }
+
+    fn render(format: &str) -> String {
+        let template: Template = format.parse().unwrap();
+        template.render(&Sanitizer::default(), vars)
+    }
+
+    fn invalid(format: &str) -> bool {
+        format.parse::<Template>().is_err()
+    }
+
+    #[test]
+    fn round_trips() {
+        for format in [
+            "{nickname}/{post_id}_{index}",
+            "{nickname}/{published:%Y-%m}/{title|truncate(60)}_{index:03}",
+            "{index:3}_{title|lower|truncate(10)}",
+            "<{published} >{title}",
+            "{{literal}} <<and>> more",
+        ] {
+            let template: Template = format.parse().unwrap();
+            assert_eq!(template.to_string(), format);
+        }
+    }
+
+    #[test]
+    fn renders_fields() {
+        assert_eq!(render("{nickname}/{post_id}_{index}"), "Alice/100_7");
+        assert_eq!(render("{published}"), "2024-05-01");
+        // a value looking like a placeholder is not substituted again
+        let template: Template = "{title}".parse().unwrap();
+        let rendered = template.render(&Sanitizer::default(), |key| match key {
+            Key::Title => Some(Value::Str("{index}")),
+            key => vars(key),
+        });
+        assert_eq!(rendered, "{index}");
+    }
+
+    #[test]
+    fn tells_used_placeholders() {
+        let template: Template = "{nickname}/<{published:%Y} >{title}".parse().unwrap();
+        assert!(template.uses(Key::Published));
+        assert!(template.uses(Key::Nickname));
+        assert!(!template.uses(Key::Added));
+        assert!(!template.uses(Key::Index));
+    }
+
+    #[test]
+    fn normalizes_separators() {
+        assert_eq!(render("/{nickname}\\{post_id}"), "Alice/100");
+    }
+
+    #[test]
+    fn unescapes_literals() {
+        assert_eq!(render("{{{post_id}}}"), "{100}");
+        assert_eq!(render("<<{post_id}>>"), "<100>");
+    }
+
+    #[test]
+    fn refuses_unbalanced() {
+        assert!(invalid("{title"));
+        assert!(invalid("title}"));
+        assert!(invalid("<{title}"));
+        assert!(invalid("{title}>"));
+        assert!(invalid("<a<{title}>>"));
+        assert!(invalid("{unknown}"));
+    }
+
+    #[test]
+    fn drops_incomplete_optionals() {
+        assert_eq!(render("<{published:%Y} - >{title}"), "2024 - Hello World");
+        assert_eq!(render("<{hash} - >{title}"), "Hello World");
+        // empty values count as missing
+        assert_eq!(render("{title}< [{tags}]>"), "Hello World");
+        // so do missing ones outside of optionals, which are left empty
+        assert_eq!(render("{hash}_{index}"), "_7");
+    }
+
+    #[test]
+    fn applies_specs() {
+        assert_eq!(render("{index:03}"), "007");
+        assert_eq!(render("{index:3}"), "  7");
+        assert_eq!(render("{published:%Y-%m}"), "2024-05");
+        // values never create folders
+        assert_eq!(render("{published:%Y/%m}"), "2024／05");
+        assert!(invalid("{index:+3}"));
+        assert!(invalid("{index:-3}"));
+        assert!(invalid("{index:x}"));
+        assert!(invalid("{title:03}"));
+    }
+
+    #[test]
+    fn applies_filters() {
+        assert_eq!(render("{title|truncate(5)}"), "Hello");
+        assert_eq!(render("{title|lower}"), "hello world");
+        assert_eq!(render("{title|upper|truncate(3)}"), "HEL");
+        assert!(invalid("{title|truncate}"));
+        assert!(invalid("{title|truncate(x)}"));
+        assert!(invalid("{title|truncate(3}"));
+        assert!(invalid("{title|reverse}"));
+    }
+
+    #[test]
+    fn derives_post_templates() {
+        let post = |format: &str| {
+            format
+                .parse::<Template>()
+                .unwrap()
+                .for_post("md")
+                .to_string()
+        };
+        assert_eq!(
+            post("{nickname}/{post_id}_{index}"),
+            "{nickname}/{post_id}.md"
+        );
+        assert_eq!(
+            post("{nickname}/{post_id}/{index}"),
+            "{nickname}/{post_id}/post.md"
+        );
+        assert_eq!(
+            post("{nickname}/{index}"),
+            "{nickname}/{index}{post_id}_{title}.md"
+        );
+    }
+
+    #[test]
+    fn tells_the_last_field() {
+        let template: Template = "{post_id}_{filename}".parse().unwrap();
+        assert!(template.ends_with(&[Key::Filename, Key::Ext]));
+        let template: Template = "{filename}.bin".parse().unwrap();
+        assert!(!template.ends_with(&[Key::Filename, Key::Ext]));
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    collections::HashSet,
+    iter,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncReadExt};
+use yaks_common::Range;
+
+use crate::{
+    Control, PARTS_SUFFIX, Source,
+    file::{self, Browse, Browsed, File, Known, Layout, Skip},
+};
+
+/// How the files of an artist in the output folder compare to the ones on the site.
+#[derive(Debug, Default)]
+pub struct Audit {
+    /// Files on the site but not on disk.
+    pub missing: Vec<File>,
+    /// Files on disk whose content does not match their hash, or can not be read.
+    pub corrupt: Vec<File>,
+    /// Files in the folders of the artist that are not on the site.
+    pub extra: Vec<PathBuf>,
+    /// Downloads that never finished.
+    pub stale: Vec<PathBuf>,
+    /// Files whose content matches their hash.
+    pub verified: usize,
+    /// Files on disk without a hash to check against.
+    pub unverified: usize,
+    /// Errors met while browsing the posts.
+    pub errors: Vec<crate::Error>,
+}
+
+/// Compare the files of the artist at `url` with the ones in the output folder.
+pub(crate) async fn audit<S: Source>(
+    url: &str,
+    range: Range,
+    layout: Layout,
+    linked: bool,
+    source: Arc<S>,
+) -> anyhow::Result<Audit> {
+    // the same files a download would collect
+    let (platform, user_id) = source.parse_url(url)?;
+    let profile = source.profile(platform, user_id).await?;
+    let mut profiles = vec![profile];
+    if linked {
+        // the files of a missing account would all look extra
+        for linked in source.linked(&profiles[0]).await? {
+            profiles.push(linked?);
+        }
+    }
+    let mut posts = Vec::new();
+    for profile in profiles {
+        let scraped = source.posts(&profile, range).await?;
+        let profile = Arc::new(profile);
+        posts.extend(scraped.into_iter().map(|post| (Arc::clone(&profile), post)));
+    }
+    let (error_tx, errors) = async_channel::unbounded();
+    let known = Known::new(&layout, linked, None);
+    let files_rx = file::collect_files(
+        posts,
+        layout,
+        known,
+        source,
+        error_tx,
+        None,
+        &Control::default(),
+    );
+
+    let mut res = Audit::default();
+    let mut present = Vec::new();
+    let mut planned = HashSet::new();
+    let mut sidecars = HashSet::new();
+    while let Ok(browse) = files_rx.recv().await {
+        let Browse::Done(Browsed {
+            collected: (files, skipped),
+            sidecars: saved,
+            ..
+        }) = browse
+        else {
+            continue;
+        };
+        sidecars.extend(saved);
+        for file in files {
+            planned.insert(file.path());
+            res.missing.push(file);
+        }
+        for (file, skip) in skipped {
+            if skip == Skip::Exists {
+                planned.insert(file.path());
+                present.push(file);
+            }
+        }
+    }
+    res.errors.extend(iter::from_fn(|| errors.try_recv().ok()));
+
+    // rehash what is on disk
+    for file in present {
+        let Some(hash) = &file.origin.hash else {
+            res.unverified += 1;
+            continue;
+        };
+        // what can not be read can not be trusted either
+        match sha256(&file.path()).await {
+            Ok(actual) if actual.eq_ignore_ascii_case(hash) => res.verified += 1,
+            _ => res.corrupt.push(file),
+        }
+    }
+
+    // look around the planned files for the rest
+    let dirs = planned
+        .iter()
+        .filter_map(|path| path.parent())
+        .collect::<HashSet<_>>();
+    for dir in dirs {
+        let Ok(mut entries) = fs::read_dir(dir).await else {
+            continue;
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if planned.contains(&path) || !entry.file_type().await?.is_file() {
+                continue;
+            }
+            if name.ends_with(PARTS_SUFFIX) {
+                res.stale.push(path);
+            } else if !name.starts_with('.') && !sidecars.contains(&path) {
+                // hidden files are the ones of yaks, or at least not of the posts
+                res.extra.push(path);
+            }
+        }
+    }
+    res.stale.sort();
+    res.extra.sort();
+    Ok(res)
+}
+
+impl Audit {
+    /// Remove the corrupt files and stale downloads,
+    /// so the next run downloads them again.
+    pub async fn clean(&self) -> anyhow::Result<()> {
+        let corrupt = self.corrupt.iter().map(File::path);
+        for path in corrupt.chain(self.stale.iter().cloned()) {
+            fs::remove_file(path).await?;
+        }
+        Ok(())
+    }
+}
+
+async fn sha256(path: &Path) -> anyhow::Result<String> {
+    let mut file = fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
@@ -1,15 +1,14 @@
-use anyhow::anyhow;
+use std::sync::Arc;
+
 use async_channel::{Receiver, Sender};
 use async_stream::try_stream;
-use futures::{Stream, StreamExt};
-use tokio::{
-    fs::{self},
-    io::AsyncWriteExt,
-    pin,
-};
-use yaks_common::SenderExt;
+use futures::{Stream, StreamExt, TryStreamExt};
+use yaks_common::{Closed, SenderExt};
 
-use crate::{FileID, client, file::File};
+use crate::{
+    Body, Control, FileID, Source, Storage, checkpoint::Checkpoint, extract::Extractor,
+    failure::Mismatch, file::File, hook::HookRunner, ledger::Ledger,
+};
 
 #[derive(Debug)]
 pub enum Prog {
@@ -19,22 +18,41 @@ pub enum Prog {
     Fin,
 }
 
+/// What is done with a file besides downloading it.
+#[derive(Clone)]
+pub(crate) struct Duties {
+    /// Where downloaded files are recorded.
+    pub ledger: Arc<Ledger>,
+    /// Where downloaded files are checkpointed, if the run is.
+    pub checkpoint: Option<Arc<Checkpoint>>,
+    /// Extracts downloaded archives, if asked to.
+    pub extractor: Option<Arc<Extractor>>,
+    pub hooks: Arc<HookRunner>,
+    /// Where downloaded files are written.
+    pub storage: Arc<dyn Storage>,
+}
+
 /// Start a fixed number of workers.
-/// The workers will drain the files from the receiver
-/// and report the progress in to the `progress` sender
-pub fn start_workers(
+/// The workers will drain the files from the receiver, download them from the source
+/// and report the progress in to the `progress` sender.
+/// Downloaded files are recorded in the ledger, and archives are extracted if asked to.
+pub(crate) fn start_workers<S: Source>(
     workers: u8,
     files: Receiver<File>,
+    source: Arc<S>,
+    duties: Duties,
     errors: Sender<crate::Error>,
+    control: &Control,
 ) -> Receiver<(FileID, Prog)> {
     let (tx, rx) = async_channel::unbounded();
     for _ in 0..workers {
         let files = files.clone();
         let progress = tx.clone();
         let errors = errors.clone();
-        tokio::spawn(async move {
-            work(files, progress, errors).await;
-        });
+        let source = Arc::clone(&source);
+        let duties = duties.clone();
+        let ctrl = control.clone();
+        control.spawn(async move { work(files, source, duties, progress, errors, ctrl).await });
     }
     rx
 }
@@ -43,54 +61,113 @@ pub fn start_workers(
 /// the function drains the streams and:
 /// 1. report progress in to the sender
 /// 2. capture yielded errors and send them... somewhere?
-async fn work(files: Receiver<File>, tx: Sender<(FileID, Prog)>, errors: Sender<crate::Error>) {
-    while let Ok(file) = files.recv().await {
+async fn work<S: Source>(
+    files: Receiver<File>,
+    source: Arc<S>,
+    Duties {
+        ledger,
+        checkpoint,
+        extractor,
+        hooks,
+        storage,
+    }: Duties,
+    tx: Sender<(FileID, Prog)>,
+    errors: Sender<crate::Error>,
+    control: Control,
+) -> Result<(), Closed> {
+    while control.proceed().await {
+        let Ok(file) = files.recv().await else {
+            break;
+        };
         let id = file.id();
-        let stream = download(file.clone());
-        pin!(stream);
-        tx.send_or_panic((id, Prog::Enqueue)).await;
-        while let Some(progress) = stream.next().await {
+        let mut stream = Box::pin(download(file.clone(), &*source, &*storage));
+        tx.deliver((id, Prog::Enqueue)).await?;
+        let mut size = 0;
+        loop {
+            let progress = tokio::select! {
+                progress = stream.next() => progress,
+                _ = control.cancelled() => {
+                    // stop writing before discarding
+                    drop(stream);
+                    let _ = storage.discard(&file.dest).await;
+                    return Ok(());
+                }
+            };
+            let Some(progress) = progress else {
+                break;
+            };
             match progress {
                 // todo: too much clone here
-                Ok(progress) => tx.send_or_panic((id, progress)).await,
+                Ok(progress) => {
+                    if let Prog::Init(total) = progress {
+                        size = total;
+                    }
+                    let fin = matches!(progress, Prog::Fin);
+                    // the file is saved whatever happens to its record
+                    if fin && let Err(e) = ledger.record(&file, size).await {
+                        errors.deliver(crate::Error::Ledger(Some(id), e)).await?;
+                    }
+                    if tx.deliver((id, progress)).await.is_err() {
+                        // a committed file stays, a partial one goes like on cancel
+                        if !fin {
+                            drop(stream);
+                            let _ = storage.discard(&file.dest).await;
+                        }
+                        return Err(Closed);
+                    }
+                    if fin {
+                        if let Some(checkpoint) = &checkpoint
+                            && let Err(e) = checkpoint.finished(&file).await
+                        {
+                            errors.deliver(crate::Error::Checkpoint(e)).await?;
+                        }
+                        if let Some(extractor) = &extractor
+                            && let Err(e) = extractor.run(file.path()).await
+                        {
+                            errors.deliver(crate::Error::Extract(id, e)).await?;
+                        }
+                        hooks.file_done(&file).await;
+                    }
+                }
                 Err(e) => {
+                    // nothing is resumed from a partial download
+                    let _ = storage.discard(&file.dest).await;
                     let e = crate::Error::Download(id, e);
-                    errors.send_or_panic(e).await;
+                    errors.deliver(e).await?;
+                    hooks.file_failed(&file).await;
                     break;
                 }
             }
         }
     }
+    Ok(())
 }
 
 /// return a stream of progress (and errors some time)
-fn download(file: File) -> impl Stream<Item = anyhow::Result<Prog>> {
+fn download<'a>(
+    file: File,
+    source: &'a impl Source,
+    storage: &'a dyn Storage,
+) -> impl Stream<Item = anyhow::Result<Prog>> + 'a {
     try_stream! {
-        // setting up the output file and the http response
-        let parent = file.dest.parent().unwrap();
-        let mut dest = {
-            fs::create_dir_all(parent).await?;
-            fs::File::create(&file.dest).await?
-        };
-        let mut resp = client()
-            .get(file.url.as_ref())
-            .send()
-            .await?
-            .error_for_status()?;
-        let total = resp
-            .content_length()
-            .ok_or(anyhow!("content-length is missing"))?;
+        // setting up the output file and the body
+        storage.create(&file.dest).await?;
+        let Body { size: total, mut chunks } = source.body(&file).await?;
         yield Prog::Init(total);
         // download by chunks
+        let mut received = 0;
         loop {
-            match resp.chunk().await? {
+            match chunks.try_next().await? {
                 Some(chunk) => {
-                    dest.write_all(&chunk).await?;
+                    storage.append(&file.dest, &chunk).await?;
+                    received += chunk.len() as u64;
                     yield Prog::Chunk(chunk.len() as u64);
                 }
                 None => {
-                    let real_dest = parent.join(file.filename.as_ref());
-                    fs::rename(&file.dest, real_dest).await?;
+                    if received != total {
+                        Err(Mismatch { expected: total, received })?;
+                    }
+                    storage.commit(&file.dest, &file.path()).await?;
                     break;
                 }
             };
@@ -1,56 +1,153 @@
 slint::include_modules!();
 
-use std::{ops::RangeInclusive, path::Path, result};
+use std::{collections::HashMap, result};
 
 use async_channel::Receiver;
-use yaks_core::{Engine, Event};
+use yaks_common::Range;
+use yaks_core::{Engine, EngineOptions, Event};
 
 pub type Result<T, E = crate::Error> = result::Result<T, E>;
 pub type Error = yaks_core::Error;
 
-#[allow(unused)]
 #[tokio::main]
 async fn main() {
     let ui = MainWindow::new().unwrap();
     let ui_handle = ui.as_weak();
     ui.on_download(move |link, out, format, from, to, workers| {
         let ui = ui_handle.unwrap();
-        let engine = Engine::default();
-        // check if the link is legal...
-        // maybe it's time for the engine to do it?
-        let out = Path::new(out.as_str().to_string().leak());
-        let format = format.to_string().leak();
-        let from = from.parse().unwrap_or(0);
-        let to = to.parse().unwrap_or(u64::MAX);
-        let range = RangeInclusive::new(from, to);
-        let workers = u8::try_from(workers).unwrap();
-        // engine.start(platform, user_id, range, out, format, workers);
+        let start = from.parse().unwrap_or(0);
+        let end = to.parse().unwrap_or(u64::MAX);
+        let workers = u8::try_from(workers).unwrap_or(u8::MAX);
+        let mut options = EngineOptions::new(link.as_str())
+            .range(Range { start, end })
+            .workers(workers);
+        // left empty, the defaults stay
+        if !out.is_empty() {
+            options = options.out(out.as_str());
+        }
+        if !format.is_empty() {
+            match format.parse() {
+                Ok(format) => options = options.format(format),
+                Err(e) => return ui.set_error(format!("{e:#}").into()),
+            }
+        }
+        // the engine checks if the link is legal
+        let engine = match Engine::new(options) {
+            Ok(engine) => engine,
+            Err(e) => return ui.set_error(format!("{e:#}").into()),
+        };
+        // start over from a clean window
+        ui.set_process("Starting".into());
+        ui.set_error("".into());
+        ui.set_done(0);
+        ui.set_total(0);
+        ui.set_received("".into());
+        ui.set_file("".into());
+        ui.set_file_cur(0);
+        ui.set_file_total(1);
+        let (rx, _control) = engine.start();
+        slint::spawn_local(handle(rx, ui)).unwrap();
     });
     ui.run().unwrap();
 }
 
-#[allow(unused)]
 async fn handle(rx: Receiver<crate::Result<Event>>, ui: MainWindow) {
+    let mut names = HashMap::new();
+    let mut current = None;
+    let mut received = 0;
+    // the report comes right before `Clear`, and may follow a failure
+    let mut reported = false;
+    let mut failed = false;
     while let Ok(event) = rx.recv().await {
         match event {
             Ok(event) => match event {
-                Event::Profile => todo!(),
-                Event::Posts(_) => todo!(),
-                Event::PostsExhausted => todo!(),
-                Event::Files(files) => todo!(),
-                Event::FilesExhausted => todo!(),
-                Event::Enqueue(_) => todo!(),
-                Event::Init(_, _) => todo!(),
-                Event::Chunk(_, _) => todo!(),
-                Event::Fin(_) => todo!(),
-                Event::Clear => todo!(),
-            },
-            Err(e) => match e {
-                Error::Profile(e) => todo!(),
-                Error::Scrape(e) => todo!(),
-                Error::Browse(_, e) => todo!(),
-                Error::Download(_, e) => todo!(),
+                Event::Profile => ui.set_process("Collecting Posts".into()),
+                Event::Posts(posts) => {
+                    ui.set_process(format!("Collecting Posts ({posts} more)").into());
+                }
+                Event::PostsExhausted => ui.set_process("Collecting Files".into()),
+                Event::Files(files) => {
+                    ui.set_total(ui.get_total() + count(files.len()));
+                    for file in files {
+                        let path = file.path();
+                        let name = path.file_name().unwrap_or(path.as_os_str());
+                        names.insert(file.id(), name.to_string_lossy().into_owned());
+                    }
+                }
+                Event::FilesExhausted => ui.set_process("Downloading".into()),
+                Event::Init(id, size) => {
+                    // the bar follows the file started last
+                    current = Some(id);
+                    received = 0;
+                    ui.set_file(names.get(&id).cloned().unwrap_or_default().into());
+                    ui.set_file_cur(0);
+                    ui.set_file_total(count(size / 1024));
+                }
+                Event::Chunk(id, size) => {
+                    if current == Some(id) {
+                        received += size;
+                        ui.set_file_cur(count(received / 1024));
+                    }
+                }
+                Event::Fin(id) => {
+                    names.remove(&id);
+                    ui.set_done(ui.get_done() + 1);
+                }
+                Event::Clear => {
+                    if !reported && !failed {
+                        ui.set_process("Done".into());
+                    }
+                }
+                Event::Stats(stats) => {
+                    ui.set_received(format!("{} MiB", stats.bytes / (1024 * 1024)).into());
+                }
+                Event::Report(report) => {
+                    reported = true;
+                    let failures = report.failures.len();
+                    let process = match (report.cancelled, failures) {
+                        (true, _) => "Cancelled".to_string(),
+                        (false, 0) => "Done".to_string(),
+                        (false, _) => format!("Done ({failures} failed)"),
+                    };
+                    if !failed {
+                        ui.set_process(process.into());
+                    }
+                }
+                Event::PostStart(..)
+                | Event::Text(..)
+                | Event::Meta(..)
+                | Event::Skipped(_)
+                | Event::Enqueue(_)
+                | Event::PostFinish(..)
+                | Event::Rate(_)
+                | Event::Retry(..) => {}
             },
+            Err(e) => {
+                match &e {
+                    // nothing is downloaded without them
+                    Error::Profile(_) | Error::Scrape(_) | Error::Ledger(None, _) => {
+                        failed = true;
+                        ui.set_process("Failed".into());
+                    }
+                    Error::Download(id, _) => {
+                        names.remove(id);
+                    }
+                    Error::Linked(_)
+                    | Error::Browse(..)
+                    | Error::Extract(..)
+                    | Error::Ledger(Some(_), _)
+                    | Error::Checkpoint(_)
+                    | Error::Storage(_)
+                    | Error::Hook(..)
+                    | Error::Escape(..) => {}
+                }
+                ui.set_error(format!("{e:#}").into());
+            }
         }
     }
 }
+
+/// Counts shown by the UI, which stops at `i32::MAX`.
+fn count(n: impl TryInto<i32>) -> i32 {
+    n.try_into().unwrap_or(i32::MAX)
+}
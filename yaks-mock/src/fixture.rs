@@ -0,0 +1,217 @@
+use std::time::Duration;
+
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+
+/// Everything the mock server knows about, and how badly it behaves.
+#[derive(Debug, Clone, Default)]
+pub struct Fixtures {
+    pub artists: Vec<Artist>,
+    pub quirks: Quirks,
+}
+
+impl Fixtures {
+    pub fn artist(mut self, artist: Artist) -> Self {
+        self.artists.push(artist);
+        self
+    }
+
+    pub fn quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
+    pub(crate) fn find(&self, platform: &str, user_id: &str) -> Option<&Artist> {
+        self.artists
+            .iter()
+            .find(|a| a.platform == platform && a.user_id == user_id)
+    }
+
+    /// The file served at `path`, like `/ab/cd/{hash}.png`.
+    pub(crate) fn file(&self, path: &str) -> Option<&MockFile> {
+        let posts = self.artists.iter().flat_map(|a| &a.posts);
+        posts.flat_map(|p| &p.files).find(|f| f.path() == path)
+    }
+}
+
+/// How the server misbehaves, like the real one does now and then.
+#[derive(Debug, Clone, Default)]
+pub struct Quirks {
+    /// How many of the first requests to the API are answered with 429 Too Many Requests.
+    pub rate_limit: usize,
+    /// Seconds the server asks to wait after a 429.
+    pub retry_after: u64,
+    /// Gzip the bodies of the API without saying so in the headers.
+    pub gzip: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Artist {
+    pub platform: String,
+    pub user_id: String,
+    /// What yaks calls the nickname.
+    pub name: String,
+    /// Newest first, whatever order they are added in.
+    pub posts: Vec<MockPost>,
+    /// Accounts on other platforms, as `(platform, user_id)`.
+    pub links: Vec<(String, String)>,
+}
+
+impl Artist {
+    pub fn new(platform: &str, user_id: &str, name: &str) -> Self {
+        Self {
+            platform: platform.into(),
+            user_id: user_id.into(),
+            name: name.into(),
+            posts: Vec::new(),
+            links: Vec::new(),
+        }
+    }
+
+    pub fn post(mut self, post: MockPost) -> Self {
+        self.posts.push(post);
+        self.posts.sort_by_key(|p| std::cmp::Reverse(p.id));
+        self
+    }
+
+    pub fn link(mut self, platform: &str, user_id: &str) -> Self {
+        self.links.push((platform.into(), user_id.into()));
+        self
+    }
+
+    pub(crate) fn profile(&self) -> Value {
+        json!({
+            "name": self.name,
+            "public_id": format!("{}-{}", self.platform, self.user_id),
+            "post_count": self.posts.len(),
+        })
+    }
+
+    pub(crate) fn links(&self) -> Value {
+        let links = self.links.iter().map(|(platform, user_id)| {
+            json!({
+                "service": platform,
+                "id": user_id,
+            })
+        });
+        Value::Array(links.collect())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MockPost {
+    pub id: u64,
+    pub title: String,
+    /// In ISO 8601, like `2024-05-01T12:00:00`.
+    pub published: Option<String>,
+    /// In HTML.
+    pub text: String,
+    pub tags: Vec<String>,
+    pub files: Vec<MockFile>,
+}
+
+impl MockPost {
+    pub fn new(id: u64, title: &str) -> Self {
+        Self {
+            id,
+            title: title.into(),
+            published: Some("2024-05-01T12:00:00".into()),
+            text: String::new(),
+            tags: Vec::new(),
+            files: Vec::new(),
+        }
+    }
+
+    pub fn text(mut self, text: &str) -> Self {
+        self.text = text.into();
+        self
+    }
+
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tags.push(tag.into());
+        self
+    }
+
+    pub fn file(mut self, file: MockFile) -> Self {
+        self.files.push(file);
+        self
+    }
+
+    /// As listed among the posts of the artist.
+    pub(crate) fn summary(&self) -> Value {
+        json!({
+            "id": self.id.to_string(),
+            "title": self.title,
+            "published": self.published,
+            "added": self.published,
+            "edited": null,
+        })
+    }
+
+    /// As browsed, with its files served by `server`.
+    pub(crate) fn detail(&self, server: &str) -> Value {
+        let previews = self.files.iter().map(|file| {
+            json!({
+                "type": "thumbnail",
+                "name": file.name,
+                "path": file.path(),
+                "server": server,
+            })
+        });
+        json!({
+            "post": {
+                "id": self.id.to_string(),
+                "title": self.title,
+                "content": self.text,
+                "tags": self.tags,
+            },
+            "previews": previews.collect::<Vec<_>>(),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MockFile {
+    pub name: String,
+    pub content: Vec<u8>,
+    pub transfer: Transfer,
+}
+
+impl MockFile {
+    pub fn new(name: &str, content: impl Into<Vec<u8>>) -> Self {
+        Self {
+            name: name.into(),
+            content: content.into(),
+            transfer: Transfer::Whole,
+        }
+    }
+
+    pub fn transfer(mut self, transfer: Transfer) -> Self {
+        self.transfer = transfer;
+        self
+    }
+
+    /// The SHA-256 of the content, which the file is named after on the server.
+    pub fn hash(&self) -> String {
+        format!("{:x}", Sha256::digest(&self.content))
+    }
+
+    /// Where the file is served, under `/data`.
+    pub fn path(&self) -> String {
+        let hash = self.hash();
+        let ext = self.name.rsplit_once('.').map_or("bin", |(_, ext)| ext);
+        format!("/{}/{}/{hash}.{ext}", &hash[..2], &hash[2..4])
+    }
+}
+
+/// How the content of a file is sent.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Transfer {
+    #[default]
+    Whole,
+    /// Chunks of `chunk` bytes, `delay` apart.
+    Slow { chunk: usize, delay: Duration },
+    /// Only the first bytes, then the connection is closed.
+    /// The announced size is still the whole one.
+    Truncated(usize),
+}
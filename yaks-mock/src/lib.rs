@@ -0,0 +1,204 @@
+//! A local server answering like the Kemono API, from fixtures.
+//!
+//! Runs are driven against it so the engine can be tested offline,
+//! including the ways the real server misbehaves.
+
+use std::{
+    io::{self, Write},
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use flate2::{Compression, write::GzEncoder};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+    time,
+};
+
+mod fixture;
+
+pub use fixture::{Artist, Fixtures, MockFile, MockPost, Quirks, Transfer};
+
+/// Posts per page, like the real API.
+const PAGE_SIZE: usize = 50;
+
+/// Serves the fixtures on a local port until dropped.
+pub struct MockServer {
+    url: String,
+    state: Arc<State>,
+    task: JoinHandle<()>,
+}
+
+struct State {
+    fixtures: Fixtures,
+    url: String,
+    /// Path of every request, in order.
+    requests: Mutex<Vec<String>>,
+    /// Requests answered with 429 so far.
+    rejected: AtomicUsize,
+}
+
+impl MockServer {
+    pub async fn start(fixtures: Fixtures) -> io::Result<Self> {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let state = Arc::new(State {
+            fixtures,
+            url: url.clone(),
+            requests: Mutex::new(Vec::new()),
+            rejected: AtomicUsize::new(0),
+        });
+        let serving = Arc::clone(&state);
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let state = Arc::clone(&serving);
+                tokio::spawn(async move {
+                    // a client giving up halfway is none of the server's business
+                    let _ = serve(stream, &state).await;
+                });
+            }
+        });
+        Ok(Self { url, state, task })
+    }
+
+    /// Where the server is, to be given as the site of the engine.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The path of every request so far, in order.
+    pub fn requests(&self) -> Vec<String> {
+        self.state.requests.lock().unwrap().clone()
+    }
+
+    /// How many requests have been answered with 429 Too Many Requests.
+    pub fn rejected(&self) -> usize {
+        self.state.rejected.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// What is sent back.
+enum Reply {
+    Json(Value),
+    File(Vec<u8>, Transfer),
+    TooManyRequests,
+    NotFound,
+}
+
+/// Answer a single request, then close the connection.
+async fn serve(mut stream: TcpStream, state: &State) -> io::Result<()> {
+    let mut reader = BufReader::new(&mut stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let path = line.split_whitespace().nth(1).unwrap_or("/").to_string();
+    // the headers are of no interest
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    state.requests.lock().unwrap().push(path.clone());
+
+    let reply = route(&path, state);
+    let (status, body, transfer, extra) = match reply {
+        Reply::Json(json) => {
+            let mut body = serde_json::to_vec(&json)?;
+            if state.fixtures.quirks.gzip {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&body)?;
+                body = encoder.finish()?;
+            }
+            ("200 OK", body, Transfer::Whole, String::new())
+        }
+        Reply::File(content, transfer) => ("200 OK", content, transfer, String::new()),
+        Reply::TooManyRequests => {
+            let retry_after = state.fixtures.quirks.retry_after;
+            let extra = format!("Retry-After: {retry_after}\r\n");
+            ("429 Too Many Requests", Vec::new(), Transfer::Whole, extra)
+        }
+        Reply::NotFound => ("404 Not Found", Vec::new(), Transfer::Whole, String::new()),
+    };
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Length: {}\r\n{extra}Connection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    match transfer {
+        Transfer::Whole => stream.write_all(&body).await?,
+        Transfer::Slow { chunk, delay } => {
+            for chunk in body.chunks(chunk.max(1)) {
+                stream.write_all(chunk).await?;
+                stream.flush().await?;
+                time::sleep(delay).await;
+            }
+        }
+        Transfer::Truncated(sent) => stream.write_all(&body[..sent.min(body.len())]).await?,
+    }
+    stream.shutdown().await
+}
+
+fn route(path: &str, state: &State) -> Reply {
+    let fixtures = &state.fixtures;
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+    if let Some(path) = path.strip_prefix("/data") {
+        return match fixtures.file(path) {
+            Some(file) => Reply::File(file.content.clone(), file.transfer),
+            None => Reply::NotFound,
+        };
+    }
+    let Some(path) = path.strip_prefix("/api/v1/") else {
+        return Reply::NotFound;
+    };
+    let limit = fixtures.quirks.rate_limit;
+    let rejected = state
+        .rejected
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+            (n < limit).then_some(n + 1)
+        });
+    if rejected.is_ok() {
+        return Reply::TooManyRequests;
+    }
+
+    let split = path.split('/').collect::<Vec<_>>();
+    let (platform, user_id, rest) = match split.as_slice() {
+        [platform, "user", user_id, rest @ ..] => (*platform, *user_id, rest),
+        _ => return Reply::NotFound,
+    };
+    let Some(artist) = fixtures.find(platform, user_id) else {
+        return Reply::NotFound;
+    };
+    match rest {
+        ["profile"] => Reply::Json(artist.profile()),
+        ["links"] => Reply::Json(artist.links()),
+        ["posts"] => {
+            let offset = query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("o="))
+                .and_then(|o| o.parse().ok())
+                .unwrap_or(0);
+            let page = artist.posts.iter().skip(offset).take(PAGE_SIZE);
+            Reply::Json(Value::Array(page.map(MockPost::summary).collect()))
+        }
+        ["post", id] => {
+            let post = artist.posts.iter().find(|p| p.id.to_string() == *id);
+            match post {
+                Some(post) => Reply::Json(post.detail(&state.url)),
+                None => Reply::NotFound,
+            }
+        }
+        _ => Reply::NotFound,
+    }
+}
@@ -0,0 +1,573 @@
+//! Runs of the engine against the mock server.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use tempfile::TempDir;
+use yaks_core::{
+    CachePolicy, Engine, EngineOptions, Error, ErrorKind, Event, Hooks, Memory, Pack,
+    Reorganization, Retry, Skip,
+};
+use yaks_mock::{Artist, Fixtures, MockFile, MockPost, MockServer, Quirks, Transfer};
+
+const PNG: &[u8] = b"\x89PNG not really an image";
+const ZIP: &[u8] = b"PK not really an archive either";
+
+fn artist() -> Artist {
+    Artist::new("fanbox", "1", "Alice")
+        .post(
+            MockPost::new(100, "First")
+                .text("<p>Hello</p>")
+                .tag("sketch")
+                .file(MockFile::new("cover.png", PNG)),
+        )
+        .post(MockPost::new(200, "Second").file(MockFile::new("pack.zip", ZIP)))
+}
+
+fn options(server: &MockServer, out: &Path) -> EngineOptions {
+    EngineOptions::new("fanbox/1")
+        .out(out)
+        .site(server.url())
+        .cache(CachePolicy::Off)
+        .retry(Retry {
+            times: 3,
+            after: Duration::ZERO,
+        })
+}
+
+/// Serve `fixtures`, and download from them into a new folder
+/// with the options of `options()` changed by `tweak`.
+async fn setup(
+    fixtures: Fixtures,
+    tweak: impl FnOnce(EngineOptions) -> EngineOptions,
+) -> (MockServer, TempDir, Engine) {
+    let server = MockServer::start(fixtures).await.unwrap();
+    let out = TempDir::new().unwrap();
+    let engine = Engine::new(tweak(options(&server, out.path()))).unwrap();
+    (server, out, engine)
+}
+
+/// Run until the engine is done with it, and sort what came out.
+async fn run(engine: Engine) -> (Vec<Event>, Vec<Error>) {
+    let (rx, control) = engine.start();
+    let mut events = Vec::new();
+    let mut errors = Vec::new();
+    while let Ok(event) = rx.recv().await {
+        match event {
+            Ok(event) => events.push(event),
+            Err(e) => errors.push(e),
+        }
+    }
+    control.shutdown().await;
+    (events, errors)
+}
+
+/// Every file under `dir`, the hidden ones of yaks excluded.
+fn files(dir: &Path) -> Vec<PathBuf> {
+    let mut res = Vec::new();
+    let Ok(entries) = fs::read_dir(dir) else {
+        return res;
+    };
+    for entry in entries {
+        let path = entry.unwrap().path();
+        if path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with(".yaks")
+        {
+            continue;
+        }
+        if path.is_dir() {
+            res.extend(files(&path));
+        } else {
+            res.push(path);
+        }
+    }
+    res.sort();
+    res
+}
+
+fn finished(events: &[Event]) -> usize {
+    events.iter().filter(|e| matches!(e, Event::Fin(_))).count()
+}
+
+#[tokio::test]
+async fn downloads_every_file() {
+    let (_server, out, engine) =
+        setup(Fixtures::default().artist(artist()), |options| options).await;
+
+    let (events, errors) = run(engine).await;
+    assert!(errors.is_empty(), "{errors:?}");
+    assert_eq!(finished(&events), 2);
+    assert!(events.iter().any(|e| matches!(e, Event::Clear)));
+
+    let files = files(out.path());
+    assert_eq!(files.len(), 2, "{files:?}");
+    let mut contents = files
+        .iter()
+        .map(|f| fs::read(f).unwrap())
+        .collect::<Vec<_>>();
+    contents.sort();
+    assert_eq!(contents, [ZIP.to_vec(), PNG.to_vec()]);
+    assert!(
+        files
+            .iter()
+            .all(|f| f.starts_with(out.path().join("Alice")))
+    );
+    assert!(out.path().join(".yaks-report.json").exists());
+    assert!(!out.path().join(".yaks-run.jsonl").exists());
+}
+
+#[tokio::test]
+async fn skips_files_in_the_ledger() {
+    let (_server, out, engine) =
+        setup(Fixtures::default().artist(artist()), |options| options).await;
+    run(engine.clone()).await;
+    for file in files(out.path()) {
+        fs::remove_file(file).unwrap();
+    }
+
+    let (events, errors) = run(engine).await;
+    assert!(errors.is_empty(), "{errors:?}");
+    assert_eq!(finished(&events), 0);
+    let archived = events
+        .iter()
+        .filter_map(|e| match e {
+            Event::Skipped(skipped) => Some(skipped),
+            _ => None,
+        })
+        .flatten()
+        .filter(|(_, skip)| matches!(skip, Skip::Archived))
+        .count();
+    assert_eq!(archived, 2);
+    assert!(files(out.path()).is_empty());
+}
+
+#[tokio::test]
+async fn resumes_cancelled_runs() {
+    let slow = Transfer::Slow {
+        chunk: 4,
+        delay: Duration::from_millis(300),
+    };
+    let pack = MockFile::new("pack.zip", ZIP).transfer(slow);
+    let artist = Artist::new("fanbox", "1", "Alice")
+        .post(MockPost::new(100, "First").file(MockFile::new("cover.png", PNG)))
+        .post(MockPost::new(200, "Second").file(pack.clone()));
+    let (server, out, engine) = setup(Fixtures::default().artist(artist), |options| options).await;
+
+    // cancel once the cover is done and the pack is on its way
+    let (rx, control) = engine.start();
+    let (mut started, mut done) = (0, 0);
+    while let Ok(event) = rx.recv().await {
+        match event {
+            Ok(Event::Init(..)) => started += 1,
+            Ok(Event::Fin(_)) => done += 1,
+            _ => {}
+        }
+        if started == 2 && done == 1 {
+            control.cancel();
+        }
+    }
+    control.shutdown().await;
+    assert_eq!(done, 1);
+    assert!(out.path().join(".yaks-run.jsonl").exists());
+    let seen = server.requests().len();
+
+    let engine = Engine::new(options(&server, out.path()).resume(true)).unwrap();
+    let (events, errors) = run(engine).await;
+    assert!(errors.is_empty(), "{errors:?}");
+    assert_eq!(finished(&events), 1);
+    let requests = server.requests().split_off(seen);
+    // neither browsed again nor downloaded again
+    assert!(
+        requests.iter().all(|r| !r.contains("/post/")),
+        "{requests:?}"
+    );
+    assert_eq!(
+        requests
+            .iter()
+            .filter(|r| r.starts_with("/data"))
+            .collect::<Vec<_>>(),
+        [&format!("/data{}", pack.path())]
+    );
+    assert_eq!(files(out.path()).len(), 2);
+    assert!(!out.path().join(".yaks-run.jsonl").exists());
+}
+
+#[tokio::test]
+async fn reads_gzip_without_header() {
+    let quirks = Quirks {
+        gzip: true,
+        ..Default::default()
+    };
+    let fixtures = Fixtures::default().artist(artist()).quirks(quirks);
+    let (_server, _out, engine) = setup(fixtures, |options| options).await;
+
+    let (events, errors) = run(engine).await;
+    assert!(errors.is_empty(), "{errors:?}");
+    assert_eq!(finished(&events), 2);
+}
+
+#[tokio::test]
+async fn retries_after_too_many_requests() {
+    let quirks = Quirks {
+        rate_limit: 1,
+        retry_after: 0,
+        ..Default::default()
+    };
+    let fixtures = Fixtures::default().artist(artist()).quirks(quirks);
+    let (server, _out, engine) = setup(fixtures, |options| options).await;
+
+    let (events, errors) = run(engine).await;
+    assert!(errors.is_empty(), "{errors:?}");
+    assert_eq!(server.rejected(), 1);
+    assert!(events.iter().any(|e| matches!(e, Event::Retry(..))));
+    assert_eq!(finished(&events), 2);
+}
+
+#[tokio::test]
+async fn gives_up_when_always_rejected() {
+    let quirks = Quirks {
+        rate_limit: usize::MAX,
+        retry_after: 0,
+        ..Default::default()
+    };
+    let fixtures = Fixtures::default().artist(artist()).quirks(quirks);
+    let (_server, _out, engine) = setup(fixtures, |options| {
+        options.retry(Retry {
+            times: 1,
+            after: Duration::ZERO,
+        })
+    })
+    .await;
+
+    let (events, errors) = run(engine).await;
+    assert_eq!(finished(&events), 0);
+    assert!(
+        matches!(errors.as_slice(), [Error::Profile(_)]),
+        "{errors:?}"
+    );
+    assert_eq!(errors[0].kind(), ErrorKind::RateLimit);
+}
+
+#[tokio::test]
+async fn waits_for_slow_transfers() {
+    let slow = Transfer::Slow {
+        chunk: 4,
+        delay: Duration::from_millis(20),
+    };
+    let artist = Artist::new("fanbox", "1", "Alice")
+        .post(MockPost::new(100, "Slow").file(MockFile::new("cover.png", PNG).transfer(slow)));
+    let (_server, out, engine) = setup(Fixtures::default().artist(artist), |options| options).await;
+
+    let (events, errors) = run(engine).await;
+    assert!(errors.is_empty(), "{errors:?}");
+    let chunks = events
+        .iter()
+        .filter(|e| matches!(e, Event::Chunk(..)))
+        .count();
+    assert!(chunks > 1);
+    let files = files(out.path());
+    assert_eq!(files.len(), 1);
+    assert_eq!(fs::read(&files[0]).unwrap(), PNG);
+}
+
+#[tokio::test]
+async fn fails_truncated_transfers() {
+    let artist = Artist::new("fanbox", "1", "Alice").post(
+        MockPost::new(100, "Broken")
+            .file(MockFile::new("cover.png", PNG).transfer(Transfer::Truncated(8)))
+            .file(MockFile::new("pack.zip", ZIP)),
+    );
+    let (_server, out, engine) = setup(Fixtures::default().artist(artist), |options| options).await;
+
+    let (events, errors) = run(engine).await;
+    assert_eq!(finished(&events), 1);
+    assert!(
+        matches!(errors.as_slice(), [Error::Download(..)]),
+        "{errors:?}"
+    );
+    assert_eq!(errors[0].kind(), ErrorKind::Network);
+    // neither the partial file nor its leftovers are kept
+    let files = files(out.path());
+    assert_eq!(files.len(), 1, "{files:?}");
+    assert_eq!(fs::read(&files[0]).unwrap(), ZIP);
+}
+
+#[tokio::test]
+async fn writes_nothing_on_dry_run() {
+    let (server, out, engine) = setup(Fixtures::default().artist(artist()), |options| {
+        options.dry_run(true)
+    })
+    .await;
+
+    let (events, errors) = run(engine).await;
+    assert!(errors.is_empty(), "{errors:?}");
+    assert_eq!(finished(&events), 0);
+    assert!(fs::read_dir(out.path()).unwrap().next().is_none());
+    assert!(server.requests().iter().all(|r| !r.starts_with("/data")));
+}
+
+#[tokio::test]
+async fn writes_into_memory() {
+    let (_server, out, engine) = setup(Fixtures::default().artist(artist()), |options| {
+        options.save_text(true)
+    })
+    .await;
+    let memory = Arc::new(Memory::default());
+    let engine = engine.with_storage(memory.clone()).unwrap();
+
+    let (events, errors) = run(engine).await;
+    assert!(errors.is_empty(), "{errors:?}");
+    assert_eq!(finished(&events), 2);
+    assert!(files(out.path()).is_empty());
+    let paths = memory.paths();
+    let png = paths.iter().find(|p| memory.get(p).as_deref() == Some(PNG));
+    assert!(png.is_some(), "{paths:?}");
+    let text = paths
+        .iter()
+        .filter_map(|p| String::from_utf8(memory.get(p).unwrap()).ok())
+        .any(|text| text.contains("Hello"));
+    assert!(text, "{paths:?}");
+}
+
+#[tokio::test]
+async fn packs_every_run_anew() {
+    let packed = TempDir::new().unwrap();
+    let tar = packed.path().join("alice.tar");
+    // the second run downloads again what the first one packed
+    let (_server, out, engine) = setup(Fixtures::default().artist(artist()), |options| {
+        options
+            .ignore_ledger(true)
+            .pack(Some(Pack::Tar(tar.clone())))
+    })
+    .await;
+
+    for engine in [engine.clone(), engine] {
+        let (events, errors) = run(engine).await;
+        assert!(errors.is_empty(), "{errors:?}");
+        assert_eq!(finished(&events), 2);
+        let size = fs::metadata(&tar).unwrap().len() as usize;
+        assert!(size > PNG.len() + ZIP.len());
+        assert!(files(out.path()).is_empty());
+        assert!(!out.path().join(".yaks-spool").exists());
+    }
+}
+
+#[tokio::test]
+async fn packs_nothing_for_unknown_artists() {
+    let packed = TempDir::new().unwrap();
+    let tar = packed.path().join("bob.tar");
+    let (_server, out, engine) = setup(Fixtures::default().artist(artist()), |options| {
+        options.url("fanbox/2").pack(Some(Pack::Tar(tar.clone())))
+    })
+    .await;
+
+    let (_, errors) = run(engine).await;
+    assert!(
+        matches!(errors.as_slice(), [Error::Profile(_)]),
+        "{errors:?}"
+    );
+    assert!(!tar.exists());
+    assert!(!out.path().join(".yaks-spool").exists());
+}
+
+#[tokio::test]
+async fn reorganizes_downloaded_files() {
+    let (server, out, engine) =
+        setup(Fixtures::default().artist(artist()), |options| options).await;
+    run(engine).await;
+    let before = files(out.path());
+
+    let format = "{nickname}/{title}/{filename}".parse().unwrap();
+    let engine = Engine::new(options(&server, out.path()).format(format)).unwrap();
+    let plan = engine.reorganize().await.unwrap();
+    assert!(plan.errors.is_empty(), "{:?}", plan.errors);
+    assert!(plan.conflicts.is_empty() && plan.unmatched.is_empty());
+    let mut to = plan.moves.iter().map(|mv| mv.to()).collect::<Vec<_>>();
+    to.sort();
+    assert_eq!(
+        to,
+        [
+            Path::new("Alice/First/cover.png"),
+            Path::new("Alice/Second/pack.zip")
+        ]
+    );
+    // planning moves nothing
+    assert_eq!(files(out.path()), before);
+
+    plan.apply().await.unwrap();
+    assert_eq!(
+        fs::read(out.path().join("Alice/First/cover.png")).unwrap(),
+        PNG
+    );
+    assert_eq!(
+        fs::read(out.path().join("Alice/Second/pack.zip")).unwrap(),
+        ZIP
+    );
+    // the new paths are the ones known from now on
+    let plan = engine.reorganize().await.unwrap();
+    assert_eq!((plan.moves.len(), plan.unchanged), (0, 2));
+
+    assert_eq!(Reorganization::undo(out.path()).await.unwrap(), 2);
+    assert_eq!(files(out.path()), before);
+}
+
+#[tokio::test]
+async fn verifies_downloaded_files() {
+    let (_server, out, engine) =
+        setup(Fixtures::default().artist(artist()), |options| options).await;
+    run(engine.clone()).await;
+    fs::write(&files(out.path())[0], b"garbage").unwrap();
+
+    let audit = engine.verify().await.unwrap();
+    assert_eq!(audit.verified, 1);
+    assert_eq!(audit.corrupt.len(), 1);
+    assert!(audit.missing.is_empty());
+}
+
+#[tokio::test]
+async fn verifies_around_sidecars_and_unreadable_files() {
+    let (_server, out, engine) = setup(Fixtures::default().artist(artist()), |options| {
+        options.save_text(true).save_meta(true)
+    })
+    .await;
+    run(engine.clone()).await;
+    let alice = out.path().join("Alice");
+    assert!(alice.join("100.md").is_file() && alice.join("100.json").is_file());
+    // attachments look like sidecars sometimes
+    fs::write(alice.join("notes.json"), b"{}").unwrap();
+    fs::write(alice.join("readme.md"), b"").unwrap();
+    // a file in the way of the cover that can not be hashed
+    let cover = files(out.path())
+        .into_iter()
+        .find(|f| fs::read(f).unwrap() == PNG)
+        .unwrap();
+    fs::remove_file(&cover).unwrap();
+    fs::create_dir(&cover).unwrap();
+    fs::write(cover.join("inside"), b"").unwrap();
+
+    let audit = engine.verify().await.unwrap();
+    assert_eq!(audit.corrupt.len(), 1);
+    assert_eq!(audit.corrupt[0].path(), cover);
+    assert_eq!(
+        audit.extra,
+        [alice.join("notes.json"), alice.join("readme.md")]
+    );
+}
+
+#[tokio::test]
+async fn reports_unknown_artists() {
+    let (_server, out, engine) = setup(Fixtures::default().artist(artist()), |options| {
+        options.url("fanbox/2")
+    })
+    .await;
+
+    let (_, errors) = run(engine).await;
+    assert!(
+        matches!(errors.as_slice(), [Error::Profile(_)]),
+        "{errors:?}"
+    );
+    assert_eq!(errors[0].kind(), ErrorKind::Http(404));
+    // the report is saved even though the run never started downloading
+    assert!(out.path().join(".yaks-report.json").is_file());
+}
+
+#[tokio::test]
+async fn drops_duplicates_from_linked_accounts() {
+    let artist = artist().link("patreon", "2");
+    let mirror = Artist::new("patreon", "2", "Alice on Patreon")
+        .post(MockPost::new(300, "First, again").file(MockFile::new("cover.png", PNG)));
+    let fixtures = Fixtures::default().artist(artist).artist(mirror);
+    let (_server, out, engine) =
+        setup(fixtures, |options| options.linked(true).save_meta(true)).await;
+
+    let (events, errors) = run(engine).await;
+    assert!(errors.is_empty(), "{errors:?}");
+    assert_eq!(finished(&events), 2);
+    let (metas, files): (Vec<_>, Vec<_>) = files(out.path())
+        .into_iter()
+        .partition(|f| f.extension().is_some_and(|e| e == "json"));
+    assert_eq!(files.len(), 2, "{files:?}");
+    // every post lists the file, wherever it is kept
+    let kept = files
+        .iter()
+        .find(|f| fs::read(f).unwrap() == PNG)
+        .unwrap()
+        .strip_prefix(out.path())
+        .unwrap()
+        .to_string_lossy()
+        .replace('\\', "\\\\");
+    let listing = metas
+        .iter()
+        .map(|m| fs::read_to_string(m).unwrap())
+        .filter(|m| m.contains(&kept))
+        .count();
+    assert_eq!(listing, 2, "{metas:?}");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn downloads_despite_dead_linked_accounts() {
+    let fixtures = Fixtures::default().artist(artist().link("patreon", "404"));
+    let (_server, _out, engine) = setup(fixtures, |options| options.linked(true)).await;
+
+    let (events, errors) = run(engine).await;
+    assert!(
+        matches!(errors.as_slice(), [Error::Linked(_)]),
+        "{errors:?}"
+    );
+    assert_eq!(errors[0].kind(), ErrorKind::Http(404));
+    assert_eq!(finished(&events), 2);
+}
+
+#[tokio::test]
+async fn refuses_posts_missing_the_dates_of_their_paths() {
+    let mut odd = MockPost::new(300, "Odd").file(MockFile::new("odd.png", PNG));
+    odd.published = Some("last tuesday".into());
+    let fixtures = Fixtures::default().artist(artist().post(odd));
+    // not in the paths, the date does not matter
+    let (server, _out, engine) = setup(fixtures, |options| options).await;
+    let (events, errors) = run(engine).await;
+    assert!(errors.is_empty(), "{errors:?}");
+    assert_eq!(finished(&events), 3);
+
+    let out = TempDir::new().unwrap();
+    let format = "{nickname}/{published:%Y}/{post_id}_{index}"
+        .parse()
+        .unwrap();
+    let engine = Engine::new(options(&server, out.path()).format(format)).unwrap();
+    let (events, errors) = run(engine).await;
+    assert!(
+        matches!(errors.as_slice(), [Error::Browse(300, _)]),
+        "{errors:?}"
+    );
+    assert_eq!(finished(&events), 2);
+}
+
+#[tokio::test]
+async fn runs_hooks_for_skipped_posts() {
+    let (_server, out, engine) = setup(Fixtures::default().artist(artist()), |options| {
+        let log = options.out.join(".yaks-hooks.log");
+        options.hooks(Hooks {
+            on_post: Some(format!("sh -c 'echo {{post_id}} >> {}'", log.display())),
+            ..Default::default()
+        })
+    })
+    .await;
+    let log = out.path().join(".yaks-hooks.log");
+    run(engine.clone()).await;
+    let posts = |log: &Path| fs::read_to_string(log).unwrap_or_default().lines().count();
+    assert_eq!(posts(&log), 2);
+
+    // everything is in the ledger now
+    let (events, errors) = run(engine).await;
+    assert!(errors.is_empty(), "{errors:?}");
+    assert_eq!(finished(&events), 0);
+    assert_eq!(posts(&log), 4);
+}
@@ -1,82 +1,295 @@
 use std::path::PathBuf;
 
-use anyhow::anyhow;
-use clap::Parser;
-use yaks_common::Range;
-use yaks_core::Conf;
+use anyhow::bail;
+use clap::{Parser, Subcommand};
+use yaks_common::Policy;
+use yaks_core::{CachePolicy, Collision, EngineOptions, Pack};
+
+use crate::plan;
+
+/// What to do, according to the conf and the command line.
+pub enum Command {
+    Download(Args),
+    Reorganize(ReorganizeArgs),
+    Verify(VerifyArgs),
+}
 
 pub struct Args {
-    pub url: String,
-    pub range: Range,
-    pub out: PathBuf,
-    pub format: String,
-    pub save_text: bool,
-    pub workers: u8,
+    pub options: EngineOptions,
+    pub dry_run: Option<plan::Format>,
     pub debug: bool,
 }
 
-impl Args {
+pub struct ReorganizeArgs {
+    pub options: EngineOptions,
+    pub dry_run: bool,
+    pub undo: bool,
+}
+
+pub struct VerifyArgs {
+    pub options: EngineOptions,
+    pub repair: bool,
+}
+
+impl Command {
     pub async fn from_conf_then_env() -> anyhow::Result<Self> {
-        let conf = Conf::load().await?;
+        let conf = EngineOptions::load().await?;
         let args = RawArgs::parse();
+        match args.command {
+            Some(RawCommand::Reorganize {
+                layout,
+                cache,
+                dry_run,
+                undo,
+            }) => {
+                let options = options_from_conf_then_env(conf, layout, cache)?.dry_run(dry_run);
+                let args = ReorganizeArgs {
+                    options,
+                    dry_run,
+                    undo,
+                };
+                Ok(Command::Reorganize(args))
+            }
+            Some(RawCommand::Verify {
+                url,
+                range,
+                layout,
+                linked,
+                jobs,
+                cache,
+                repair,
+            }) => {
+                let mut options = options_from_conf_then_env(conf, layout, cache)?.url(url);
+                if let Some(range) = range {
+                    options = options.range(range.parse()?);
+                }
+                if let Some(jobs) = jobs {
+                    options = options.workers(jobs);
+                }
+                options.linked |= linked;
+                Ok(Command::Verify(VerifyArgs { options, repair }))
+            }
+            None => Args::from_conf_then_env(conf, args).map(Command::Download),
+        }
+    }
+}
+
+impl Args {
+    fn from_conf_then_env(conf: EngineOptions, args: RawArgs) -> anyhow::Result<Self> {
         // configurable ones
-        // todo: let clap handle dir_next::download_dir()
-        let out = conf
-            .out
-            .or(args.out)
-            .or_else(dirs_next::download_dir)
-            .ok_or(anyhow!("Can not locate the default download folder"))?
-            .into();
-        let format = conf.format.unwrap_or(args.format).into();
-        let save_text = args.save_text;
-        let workers = conf.jobs.unwrap_or(args.jobs);
+        let mut options = options_from_conf_then_env(conf, args.layout, args.cache)?;
+        if let Some(range) = args.range {
+            options = options.range(range.parse()?);
+        }
+        if let Some(jobs) = args.jobs {
+            options = options.workers(jobs);
+        }
+        // the conf only tells how archives are extracted, `-x` tells whether they are
+        let extract = args
+            .extract
+            .then(|| options.extract.take().unwrap_or_default());
+        options.linked |= args.linked;
+        options.save_text |= args.save_text;
+        options.save_meta |= args.save_meta;
+        options.ignore_ledger |= args.ignore_ledger;
+        let pack = match (args.tar, args.zip) {
+            (Some(tar), _) => Some(Pack::Tar(tar)),
+            (_, Some(zip)) => Some(Pack::Zip(zip)),
+            (None, None) => options.pack.take(),
+        };
 
         // only present in args
-        let url = args.url.into();
-        let range = if let Some(range) = args.range {
-            range.parse()?
-        } else {
-            Range::default()
+        let dry_run = match args.dry_run.as_deref() {
+            None => None,
+            Some("json") => Some(plan::Format::Json),
+            Some("csv") => Some(plan::Format::Csv),
+            Some(_) => Some(plan::Format::Text),
         };
-        let debug = args.debug;
-        // collect
+        let options = options
+            .extract(extract)
+            .pack(pack)
+            .url(args.url.unwrap_or_default())
+            .resume(args.resume)
+            .dry_run(dry_run.is_some());
         let args = Args {
-            url,
-            range,
-            out,
-            format,
-            save_text,
-            workers,
-            debug,
+            options,
+            dry_run,
+            debug: args.debug,
         };
         Ok(args)
     }
 }
 
+/// Where and how files are saved, shared by all commands, merged onto the conf.
+///
+/// What is given on the command line wins over the conf.
+fn options_from_conf_then_env(
+    conf: EngineOptions,
+    args: LayoutArgs,
+    cache: CacheArgs,
+) -> anyhow::Result<EngineOptions> {
+    let mut options = conf;
+    if let Some(out) = args.out {
+        options = options.out(out);
+    }
+    if let Some(format) = args.format {
+        options = options.format(format.parse()?);
+    }
+    if cache.offline {
+        options = options.cache(CachePolicy::Offline);
+    } else if cache.no_cache {
+        options = options.cache(CachePolicy::Off);
+    }
+    // custom replacements of the conf give way to a policy on the command line
+    match args.sanitize.as_deref() {
+        None => {}
+        Some("portable") => options.sanitizer.policy = Policy::Portable,
+        Some("posix") => options.sanitizer.policy = Policy::Posix,
+        Some(other) => bail!("Unknown sanitization policy `{other}`"),
+    }
+    options.sanitizer.nfc |= args.nfc;
+    match args.on_collision.as_deref() {
+        None => {}
+        Some("number") => options = options.collision(Collision::Number),
+        Some("skip") => options = options.collision(Collision::Skip),
+        Some("overwrite") => options = options.collision(Collision::Overwrite),
+        Some(other) => bail!("Unknown collision policy `{other}`"),
+    }
+    Ok(options)
+}
+
 #[derive(Parser, Debug)]
-#[command(version, about = "Yet-another Kemono Scraper", long_about = None)]
+#[command(
+    version,
+    about = "Yet-another Kemono Scraper",
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+)]
 struct RawArgs {
+    #[command(subcommand)]
+    command: Option<RawCommand>,
     /// URL of the page to download.
     /// Also accepts the format {platform}/{user_id} (e.g. fanbox/123456)
     #[arg(required = true)]
-    url: String,
+    url: Option<String>,
     /// Inclusive range of IDs of posts to download.
     /// Can be specified as {min}~{max}, {min}~ or ~{max}
     #[arg(short, long)]
     range: Option<String>,
-    /// Output directory for downloaded files [default: $HOME/Downloads]
-    #[arg(short, long)]
-    out: Option<PathBuf>,
-    /// Filename format for downloaded files
-    #[arg(short, long, default_value = "{nickname}/{post_id}_{index}")]
-    format: String,
+    #[command(flatten)]
+    layout: LayoutArgs,
+    /// Pack the files into a tar archive instead, or stream it to the standard output with `-`
+    #[arg(long, conflicts_with_all = ["zip", "dry_run", "resume", "extract"])]
+    tar: Option<PathBuf>,
+    /// Pack the files into a zip archive instead
+    #[arg(long, conflicts_with_all = ["dry_run", "resume", "extract"])]
+    zip: Option<PathBuf>,
     /// Save the textual content of the post.
     #[arg(short = 't', long = "text")]
     save_text: bool,
-    /// Maximum amount of parallel jobs
-    #[arg(short, long, default_value = "5")]
-    jobs: u8,
+    /// Save the metadata of the post as a JSON file.
+    #[arg(short = 'm', long = "meta")]
+    save_meta: bool,
+    /// Also download from the accounts linked to the artist on other platforms
+    #[arg(short, long)]
+    linked: bool,
+    /// Maximum amount of parallel jobs [default: 5]
+    #[arg(short, long)]
+    jobs: Option<u8>,
+    #[command(flatten)]
+    cache: CacheArgs,
+    /// Extract downloaded `.zip` and `.7z` archives into folders next to them
+    #[arg(short = 'x', long)]
+    extract: bool,
+    /// Download files again even if the ledger says they have been downloaded before
+    #[arg(long)]
+    ignore_ledger: bool,
+    /// Continue the run interrupted in the output folder without scraping nor browsing again
+    #[arg(long, conflicts_with = "dry_run")]
+    resume: bool,
+    /// Only plan the files and print the plan as `text`, `json` or `csv`.
+    /// Nothing is written nor downloaded
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "text",
+        value_parser = ["text", "json", "csv"],
+    )]
+    dry_run: Option<String>,
     /// Switch to debug mode
     #[arg(short, long, hide = true)]
     debug: bool,
 }
+
+#[derive(Subcommand, Debug)]
+enum RawCommand {
+    /// Move the files already downloaded to where the format puts them now
+    Reorganize {
+        #[command(flatten)]
+        layout: LayoutArgs,
+        #[command(flatten)]
+        cache: CacheArgs,
+        /// Only show what would be moved
+        #[arg(long)]
+        dry_run: bool,
+        /// Move the files back to where they were before the last reorganization
+        #[arg(long, conflicts_with = "dry_run")]
+        undo: bool,
+    },
+    /// Compare the files downloaded from an artist with the ones on the site
+    Verify {
+        /// URL of the artist to verify.
+        /// Also accepts the format {platform}/{user_id} (e.g. fanbox/123456)
+        url: String,
+        /// Inclusive range of IDs of posts to verify.
+        /// Can be specified as {min}~{max}, {min}~ or ~{max}
+        #[arg(short, long)]
+        range: Option<String>,
+        #[command(flatten)]
+        layout: LayoutArgs,
+        /// Also verify the accounts linked to the artist on other platforms
+        #[arg(short, long)]
+        linked: bool,
+        /// Maximum amount of parallel jobs when repairing [default: 5]
+        #[arg(short, long)]
+        jobs: Option<u8>,
+        #[command(flatten)]
+        cache: CacheArgs,
+        /// Remove corrupt files and stale downloads, then download what is missing
+        #[arg(long)]
+        repair: bool,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct LayoutArgs {
+    /// Output directory for downloaded files [default: $HOME/Downloads]
+    #[arg(short, long)]
+    out: Option<PathBuf>,
+    /// Filename format for downloaded files [default: {nickname}/{post_id}_{index}]
+    #[arg(short, long)]
+    format: Option<String>,
+    /// Which characters are replaced in paths.
+    /// `portable` keeps paths valid on Windows, NTFS and exFAT [default: portable]
+    #[arg(long, value_parser = ["portable", "posix"])]
+    sanitize: Option<String>,
+    /// Normalize paths into Unicode NFC
+    #[arg(long)]
+    nfc: bool,
+    /// What to do when two files map to the same path.
+    /// `number` appends ` (1)`, ` (2)`... to the later ones [default: number]
+    #[arg(long, value_parser = ["number", "skip", "overwrite"])]
+    on_collision: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct CacheArgs {
+    /// Do not read nor write the cache of API responses
+    #[arg(long, conflicts_with = "offline")]
+    no_cache: bool,
+    /// Only use cached API responses. Never ask the server for them
+    #[arg(long)]
+    offline: bool,
+}
@@ -1,36 +1,59 @@
-use std::{collections::HashMap, result, time::Duration};
+use std::{collections::HashMap, io, result, time::Duration};
 
-use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget};
-use yaks_core::{Engine, Event};
+use indicatif::{HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget};
+use yaks_core::{Engine, Event, RunReport};
 
-use crate::args::Args;
+use crate::{
+    args::{Args, Command},
+    plan::Entry,
+};
 
 pub type Result<T, E = crate::Error> = result::Result<T, E>;
 pub type Error = yaks_core::Error;
 
 mod args;
+mod plan;
+mod reorganize;
 mod style;
+mod verify;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
     // args
     let Args {
-        url,
-        range,
-        out,
-        format,
-        save_text,
-        workers,
+        options,
+        dry_run,
         debug,
-    } = Args::from_conf_then_env().await?;
+    } = match Command::from_conf_then_env().await? {
+        Command::Download(args) => args,
+        Command::Reorganize(args) => return reorganize::run(args).await,
+        Command::Verify(args) => match verify::run(args).await? {
+            Some(args) => args,
+            None => return Ok(()),
+        },
+    };
 
     // let the engine run
-    let engine = Engine::default();
-    let rx = engine.start(url, range, out, format, save_text, workers);
+    let engine = Engine::new(options)?;
+    let (rx, control) = engine.start();
+    // stop cleanly on Ctrl-C, so no partial download is left behind,
+    // and right away on the second one
+    let ctrl = control.clone();
+    tokio::spawn(async move {
+        let mut pressed = false;
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if pressed {
+                std::process::exit(130);
+            }
+            pressed = true;
+            ctrl.cancel();
+        }
+    });
 
     // disable the TUI when debugging
     if debug {
         while rx.recv().await.is_ok() {}
+        control.shutdown().await;
         return Ok(());
     }
 
@@ -40,7 +63,11 @@ async fn main() -> anyhow::Result<()> {
     let mut files = HashMap::new();
     let mut browse_errors = HashMap::new();
     let mut download_errors = HashMap::new();
+    let mut storage_error = None;
+    let mut linked_error = None;
     let mut waiting = true;
+    let mut report = None;
+    let mut plan = Vec::new();
 
     // create the top banners
     mp.set_draw_target(ProgressDrawTarget::hidden());
@@ -77,8 +104,16 @@ async fn main() -> anyhow::Result<()> {
         match event {
             Ok(event) => match event {
                 Event::Profile => {
-                    fetch_profile.set_style(style::finish());
-                    fetch_profile.finish_with_message("Profile fetched");
+                    match &linked_error {
+                        None => {
+                            fetch_profile.set_style(style::finish());
+                            fetch_profile.finish_with_message("Profile fetched");
+                        }
+                        Some(e) => {
+                            fetch_profile.set_style(style::finish_with_error());
+                            fetch_profile.finish_with_message(format!("Profile fetched ({e})"));
+                        }
+                    }
                     scrape_posts = mp.add(scrape_posts);
                 }
                 Event::Posts(posts) => {
@@ -89,16 +124,28 @@ async fn main() -> anyhow::Result<()> {
                     scrape_posts.set_style(style::finish());
                     scrape_posts.finish_with_message("Posts scraped");
                     collect_files = mp.add(collect_files);
-                    download = mp.add(download);
-                    speed = mp.add(speed);
+                    if dry_run.is_none() {
+                        download = mp.add(download);
+                        speed = mp.add(speed);
+                    }
                 }
                 Event::Files(new_files) => {
                     collect_files.inc(1);
                     download.inc_length(new_files.len() as u64);
                     for file in new_files {
+                        if dry_run.is_some() {
+                            plan.push(Entry::new(&file, None).await);
+                        }
                         files.insert(file.id(), file);
                     }
                 }
+                Event::Skipped(skipped) => {
+                    if dry_run.is_some() {
+                        for (file, skip) in skipped {
+                            plan.push(Entry::new(&file, Some(skip)).await);
+                        }
+                    }
+                }
                 Event::FilesExhausted => {
                     if browse_errors.is_empty() {
                         collect_files.set_style(style::finish());
@@ -138,15 +185,60 @@ async fn main() -> anyhow::Result<()> {
                     mp.remove(&bars.remove(&id).unwrap());
                 }
                 Event::Clear => {
-                    if browse_errors.is_empty() && download_errors.is_empty() {
+                    if control.is_cancelled() {
+                        download.set_style(style::finish_with_error());
+                        download.finish_with_message("Cancelled");
+                    } else if browse_errors.is_empty()
+                        && download_errors.is_empty()
+                        && storage_error.is_none()
+                    {
                         download.set_style(style::finish());
-                        download.finish_with_message("Clear :)");
+                        match report.take() {
+                            Some(RunReport { stats, elapsed, .. }) => {
+                                download.finish_with_message(format!(
+                                    "Clear :) ({} files, {} in {})",
+                                    stats.downloaded,
+                                    HumanBytes(stats.bytes),
+                                    HumanDuration(elapsed)
+                                ));
+                            }
+                            None => download.finish_with_message("Clear :)"),
+                        }
                     } else {
                         download.set_style(style::finish_with_error());
-                        download.finish_with_message("Failed to download all files");
+                        match report.take() {
+                            Some(RunReport { failures, .. }) => {
+                                let retryable = failures.iter().filter(|f| f.retryable).count();
+                                download.finish_with_message(format!(
+                                    "Failed to download all files ({} failures, {retryable} may succeed if retried, see .yaks-report.json)",
+                                    failures.len()
+                                ));
+                            }
+                            None => download.finish_with_message("Failed to download all files"),
+                        }
                     }
                     break;
                 }
+                Event::Rate(interval) => {
+                    let rate = 1.0 / interval.as_secs_f64();
+                    collect_files.set_prefix(format!("({rate:.1} req/s)"));
+                }
+                Event::Retry(_, after) => {
+                    let retry = format!("(retrying in {})", HumanDuration(after));
+                    if scrape_posts.is_finished() {
+                        // until the next change of rate
+                        collect_files.set_prefix(retry);
+                    } else {
+                        scrape_posts.set_message(format!("Scraping posts...{retry}"));
+                    }
+                }
+                Event::Report(run) => report = Some(run),
+                // the bars keep their own counts
+                Event::PostStart(..)
+                | Event::PostFinish(..)
+                | Event::Text(..)
+                | Event::Meta(..)
+                | Event::Stats(_) => {}
             },
             Err(e) => match e {
                 Error::Profile(e) => {
@@ -159,6 +251,20 @@ async fn main() -> anyhow::Result<()> {
                     scrape_posts.finish_with_message(format!("Failed to scrape posts ({e})"));
                     break;
                 }
+                // told once the profile is fetched
+                e @ Error::Linked(_) => linked_error = Some(e),
+                e @ Error::Ledger(None, _) => {
+                    collect_files.set_style(style::finish_with_error());
+                    collect_files.finish_with_message(format!("{e}"));
+                    break;
+                }
+                Error::Ledger(Some(id), e) => {
+                    let filename = files.get(&id).unwrap().filename.as_ref();
+                    download.set_style(style::error());
+                    download.set_message(format!(
+                        "Downloading...(Failed to record {filename} in the ledger ({e}))"
+                    ));
+                }
                 Error::Browse(id, e) => {
                     collect_files.set_style(style::error());
                     collect_files.set_message(format!(
@@ -175,8 +281,52 @@ async fn main() -> anyhow::Result<()> {
                     bars.remove(&id).unwrap();
                     download_errors.insert(id, e);
                 }
+                Error::Extract(id, e) => {
+                    let filename = files.get(&id).unwrap().filename.as_ref();
+                    download.set_style(style::error());
+                    download.set_message(format!(
+                        "Downloading...(Failed to extract {filename} ({e}))"
+                    ));
+                }
+                e @ Error::Checkpoint(_) => {
+                    // a checkpoint that can not be resumed stops the run before it starts
+                    if !fetch_profile.is_finished() {
+                        fetch_profile.set_style(style::finish_with_error());
+                        fetch_profile.finish_with_message(format!("{e}"));
+                        break;
+                    }
+                    download.set_style(style::error());
+                    download.set_message(format!("Downloading...({e})"));
+                }
+                e @ Error::Storage(_) => {
+                    download.set_style(style::error());
+                    download.set_message(format!("Downloading...({e})"));
+                    storage_error = Some(e);
+                }
+                e @ Error::Hook(..) => {
+                    download.set_style(style::error());
+                    download.set_message(format!("Downloading...({e})"));
+                }
+                e @ Error::Escape(id, _) => {
+                    collect_files.set_style(style::error());
+                    collect_files.set_message(format!("Collecting files...({e})"));
+                    browse_errors.insert(id, e.into());
+                }
             },
         }
     }
+
+    // cancelled before anything was downloaded
+    if control.is_cancelled() && !download.is_finished() {
+        download.set_style(style::finish_with_error());
+        download.finish_with_message("Cancelled");
+    }
+    // whatever is left winds down once nobody listens to it
+    drop(rx);
+    control.shutdown().await;
+
+    if let Some(format) = dry_run {
+        plan::write(&plan, format, io::stdout().lock())?;
+    }
     Ok(())
 }
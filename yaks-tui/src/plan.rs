@@ -0,0 +1,98 @@
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use serde::Serialize;
+use tokio::fs;
+use yaks_core::{File, FileID, Skip};
+
+/// How the plan of a dry run is printed.
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    Text,
+    Json,
+    Csv,
+}
+
+/// A file found by a dry run.
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    id: FileID,
+    url: Box<str>,
+    dest: PathBuf,
+    /// Only known for files already on disk.
+    size: Option<u64>,
+    skip: Option<Skip>,
+}
+
+impl Entry {
+    pub async fn new(file: &File, skip: Option<Skip>) -> Self {
+        let dest = file.path();
+        let size = fs::metadata(&dest).await.ok().map(|meta| meta.len());
+        Self {
+            id: file.id(),
+            url: file.url.clone(),
+            dest,
+            size,
+            skip,
+        }
+    }
+}
+
+pub fn write(plan: &[Entry], format: Format, mut w: impl Write) -> io::Result<()> {
+    match format {
+        Format::Text => {
+            for Entry {
+                url,
+                dest,
+                size,
+                skip,
+                ..
+            } in plan
+            {
+                let status = skip.map_or("download".to_string(), |skip| skip.to_string());
+                write!(w, "{status:<9} {}  <- {url}", dest.display())?;
+                if let Some(size) = size {
+                    write!(w, " ({size} bytes)")?;
+                }
+                writeln!(w)?;
+            }
+        }
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut w, plan)?;
+            writeln!(w)?;
+        }
+        Format::Csv => {
+            writeln!(w, "id,url,dest,size,skip")?;
+            for Entry {
+                id,
+                url,
+                dest,
+                size,
+                skip,
+            } in plan
+            {
+                let size = size.map(|size| size.to_string()).unwrap_or_default();
+                let skip = skip.map(|skip| skip.to_string()).unwrap_or_default();
+                let dest = dest.to_string_lossy();
+                writeln!(
+                    w,
+                    "{},{},{},{size},{skip}",
+                    csv_field(&id.to_string()),
+                    csv_field(url),
+                    csv_field(&dest)
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
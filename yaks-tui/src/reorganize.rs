@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+use indicatif::ProgressBar;
+use yaks_core::{Engine, Move, Reorganization};
+
+use crate::{args::ReorganizeArgs, style};
+
+pub async fn run(
+    ReorganizeArgs {
+        options,
+        dry_run,
+        undo,
+    }: ReorganizeArgs,
+) -> anyhow::Result<()> {
+    if undo {
+        let undone = Reorganization::undo(&options.out).await?;
+        println!("Moved {undone} files back");
+        return Ok(());
+    }
+
+    let planning = ProgressBar::new(0);
+    planning.set_style(style::fetch_profile());
+    planning.set_message("Planning...");
+    planning.enable_steady_tick(Duration::from_millis(300));
+    let engine = Engine::new(options)?;
+    let plan = engine.reorganize().await;
+    planning.finish_and_clear();
+    let plan = plan?;
+
+    for e in &plan.errors {
+        eprintln!("error: {e}");
+    }
+    for record in &plan.unmatched {
+        eprintln!(
+            "unmatched: {} (post {} not found)",
+            record.path.display(),
+            record.post
+        );
+    }
+    for Move { from, record } in &plan.conflicts {
+        eprintln!(
+            "conflict: {} -> {} (taken)",
+            from.display(),
+            record.path.display()
+        );
+    }
+    for Move { from, record } in &plan.moves {
+        println!("{} -> {}", from.display(), record.path.display());
+    }
+    println!(
+        "{} to move, {} unchanged, {} conflicts, {} unmatched",
+        plan.moves.len(),
+        plan.unchanged,
+        plan.conflicts.len(),
+        plan.unmatched.len()
+    );
+    if dry_run || plan.moves.is_empty() {
+        return Ok(());
+    }
+    plan.apply().await?;
+    println!(
+        "Moved {} files. Run `yaks reorganize --undo` to move them back",
+        plan.moves.len()
+    );
+    Ok(())
+}
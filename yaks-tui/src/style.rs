@@ -47,7 +47,7 @@ progress_style! {
 }
 progress_style! {
     collect_files,
-    "{spinner:.blue} [{pos}/{len}] {msg}",
+    "{spinner:.blue} [{pos}/{len}] {msg} {prefix:.dim}",
     "◴◷◶◵ "
 }
 progress_style! {
@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+use indicatif::ProgressBar;
+use yaks_core::Engine;
+
+use crate::{
+    args::{Args, VerifyArgs},
+    style,
+};
+
+/// Print how the local files compare to the remote ones.
+/// Returns the download to run if they are to be repaired.
+pub async fn run(VerifyArgs { options, repair }: VerifyArgs) -> anyhow::Result<Option<Args>> {
+    let verifying = ProgressBar::new(0);
+    verifying.set_style(style::fetch_profile());
+    verifying.set_message("Verifying...");
+    verifying.enable_steady_tick(Duration::from_millis(300));
+    let engine = Engine::new(options)?;
+    let audit = engine.verify().await;
+    verifying.finish_and_clear();
+    let audit = audit?;
+
+    for e in &audit.errors {
+        eprintln!("error: {e}");
+    }
+    for file in &audit.missing {
+        println!("missing: {}", file.path().display());
+    }
+    for file in &audit.corrupt {
+        println!("corrupt: {}", file.path().display());
+    }
+    for path in &audit.stale {
+        println!("stale: {}", path.display());
+    }
+    for path in &audit.extra {
+        println!("extra: {}", path.display());
+    }
+    println!(
+        "{} verified, {} unverified, {} missing, {} corrupt, {} stale, {} extra",
+        audit.verified,
+        audit.unverified,
+        audit.missing.len(),
+        audit.corrupt.len(),
+        audit.stale.len(),
+        audit.extra.len()
+    );
+
+    let broken = !audit.missing.is_empty() || !audit.corrupt.is_empty();
+    if !repair || (!broken && audit.stale.is_empty()) {
+        return Ok(None);
+    }
+    audit.clean().await?;
+    if !broken {
+        return Ok(None);
+    }
+    // what is missing may be in the ledger
+    let args = Args {
+        options: engine.options().clone().ignore_ledger(true),
+        dry_run: None,
+        debug: false,
+    };
+    Ok(Some(args))
+}